@@ -0,0 +1,14 @@
+//! Command line flags shared by the days. Each day gets the arguments after `dayN`.
+
+/// Whether `flag` was given on the command line
+pub fn has_flag(args: &[String], flag: &str) -> bool {
+    args.iter().any(|arg| arg == flag)
+}
+
+/// The value following `flag` on the command line
+pub fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|arg| arg == flag)
+        .and_then(|i| args.get(i + 1))
+        .map(String::as_str)
+}
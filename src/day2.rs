@@ -1,6 +1,296 @@
-use std::fs::read_to_string;
+use std::{fmt, fs::read_to_string};
 
-pub fn process_input(path: &str) {
+use crate::args::has_flag;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Increasing,
+    Decreasing,
+    Either,
+}
+
+/// The rules a report has to follow to be considered safe.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SafetyPolicy {
+    pub min_step: u64,
+    pub max_step: u64,
+    pub direction: Direction,
+    /// Whether two equal levels next to each other are allowed, regardless of `min_step`
+    pub allow_plateaus: bool,
+    /// How many levels the Problem Dampener may remove
+    pub max_removals: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViolationKind {
+    DirectionChange,
+    StepTooSmall,
+    StepTooLarge,
+}
+
+/// The first pair of neighbouring levels that breaks the policy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub first: usize,
+    pub second: usize,
+    pub kind: ViolationKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Safe,
+    /// Unsafe as-is, but safe once the Problem Dampener drops the `removed` levels
+    Dampened {
+        violation: Violation,
+        removed: Vec<usize>,
+    },
+    Unsafe {
+        violation: Violation,
+    },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self.kind {
+            ViolationKind::DirectionChange => "direction change",
+            ViolationKind::StepTooSmall => "step too small",
+            ViolationKind::StepTooLarge => "step too large",
+        };
+        write!(
+            f,
+            "{} between levels {} and {}",
+            reason, self.first, self.second
+        )
+    }
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Verdict::Safe => write!(f, "safe"),
+            Verdict::Dampened { violation, removed } => {
+                write!(f, "safe after removing {:?} ({})", removed, violation)
+            }
+            Verdict::Unsafe { violation } => write!(f, "unsafe ({})", violation),
+        }
+    }
+}
+
+impl Default for SafetyPolicy {
+    fn default() -> Self {
+        SafetyPolicy {
+            min_step: 1,
+            max_step: 3,
+            direction: Direction::Either,
+            allow_plateaus: false,
+            max_removals: 0,
+        }
+    }
+}
+
+impl SafetyPolicy {
+    /// The part 2 rules: the default policy, but one level may be removed
+    pub fn with_dampener() -> Self {
+        SafetyPolicy {
+            max_removals: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Parses command line flags on top of `base`:
+    /// `--min-step N`, `--max-step N`, `--direction increasing|decreasing|either`,
+    /// `--allow-plateaus` and `--removals K`.
+    pub fn from_args(base: SafetyPolicy, args: &[String]) -> Result<Self, String> {
+        let mut policy = base;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--min-step" => policy.min_step = parse_flag_value(arg, args.next())?,
+                "--max-step" => policy.max_step = parse_flag_value(arg, args.next())?,
+                "--removals" => policy.max_removals = parse_flag_value(arg, args.next())?,
+                "--allow-plateaus" => policy.allow_plateaus = true,
+                "--direction" => {
+                    policy.direction = match args.next().map(String::as_str) {
+                        Some("increasing") => Direction::Increasing,
+                        Some("decreasing") => Direction::Decreasing,
+                        Some("either") => Direction::Either,
+                        other => return Err(format!("Invalid --direction: {:?}", other)),
+                    }
+                }
+                _ => return Err(format!("Unknown flag: {}", arg)),
+            }
+        }
+        Ok(policy)
+    }
+
+    fn is_step_allowed(&self, prev: i64, next: i64) -> bool {
+        if prev == next {
+            return self.allow_plateaus || self.min_step == 0;
+        }
+        let diff = prev.abs_diff(next);
+        diff >= self.min_step && diff <= self.max_step
+    }
+
+    /// Whether `next` may directly follow `prev` in a report heading in `direction`
+    fn is_pair_allowed(&self, direction: Direction, prev: i64, next: i64) -> bool {
+        let direction_ok = match direction {
+            Direction::Increasing => next >= prev,
+            Direction::Decreasing => next <= prev,
+            Direction::Either => true,
+        };
+        direction_ok && self.is_step_allowed(prev, next)
+    }
+
+    /// Finds the first pair of neighbouring levels that breaks the policy, ignoring the dampener.
+    /// With `Direction::Either` the first non-plateau step decides the direction.
+    pub fn first_violation(&self, report: &[i64]) -> Option<Violation> {
+        let mut direction = self.direction;
+        for (first, pair) in report.windows(2).enumerate() {
+            let (prev, next) = (pair[0], pair[1]);
+            let violation = |kind| Violation {
+                first,
+                second: first + 1,
+                kind,
+            };
+
+            if !self.is_pair_allowed(direction, prev, next) {
+                if !self.is_pair_allowed(Direction::Either, prev, next) {
+                    let kind = if prev.abs_diff(next) > self.max_step {
+                        ViolationKind::StepTooLarge
+                    } else {
+                        ViolationKind::StepTooSmall
+                    };
+                    return Some(violation(kind));
+                }
+                return Some(violation(ViolationKind::DirectionChange));
+            }
+
+            if direction == Direction::Either && next != prev {
+                direction = if next > prev {
+                    Direction::Increasing
+                } else {
+                    Direction::Decreasing
+                };
+            }
+        }
+        None
+    }
+
+    /// Checks the report and explains the outcome
+    pub fn check_report(&self, report: &[i64]) -> Verdict {
+        let violation = match self.first_violation(report) {
+            Some(violation) => violation,
+            None => return Verdict::Safe,
+        };
+        match self.find_removals(report) {
+            Some(removed) => Verdict::Dampened { violation, removed },
+            None => Verdict::Unsafe { violation },
+        }
+    }
+
+    /// Checks the report, letting the Problem Dampener remove up to `max_removals` levels
+    pub fn is_safe(&self, report: &[i64]) -> bool {
+        self.find_removals(report).is_some()
+    }
+
+    /// Finds the fewest levels (by index) that have to be removed to make the report safe, or
+    /// `None` if it needs more than `max_removals` of them.
+    pub fn find_removals(&self, report: &[i64]) -> Option<Vec<usize>> {
+        let directions: &[Direction] = match self.direction {
+            Direction::Either => &[Direction::Increasing, Direction::Decreasing],
+            _ => &[self.direction],
+        };
+
+        directions
+            .iter()
+            .filter_map(|direction| self.find_removals_heading(report, *direction))
+            .min_by_key(|removed| removed.len())
+    }
+
+    /// Single pass over the report: for each level we track, per number of removals so far, which
+    /// earlier level it can follow when kept. Only the previous `max_removals + 1` levels can be a
+    /// predecessor, so this is O(n * k^2).
+    fn find_removals_heading(&self, report: &[i64], direction: Direction) -> Option<Vec<usize>> {
+        // There's never anything to gain from removing more levels than there are
+        let k = self.max_removals.min(report.len());
+        if report.len() <= 1 {
+            return Some(Vec::new());
+        }
+
+        // reachable[i][r]: level i can be the last kept level after r removals. The value is the
+        // previously kept level, or None if i is the first kept one.
+        let mut reachable: Vec<Vec<Option<Option<usize>>>> = vec![vec![None; k + 1]; report.len()];
+        for (i, next) in report.iter().enumerate() {
+            if i <= k {
+                // Drop every level before this one
+                reachable[i][i] = Some(None);
+            }
+            for j in i.saturating_sub(k + 1)..i {
+                if !self.is_pair_allowed(direction, report[j], *next) {
+                    continue;
+                }
+                let skipped = i - j - 1;
+                for r in 0..=(k - skipped) {
+                    if reachable[j][r].is_some() && reachable[i][r + skipped].is_none() {
+                        reachable[i][r + skipped] = Some(Some(j));
+                    }
+                }
+            }
+        }
+
+        // Pick the cheapest end point, counting the trailing levels that get dropped
+        let mut best: Option<(usize, usize)> = None;
+        for (i, ends) in reachable
+            .iter()
+            .enumerate()
+            .skip(report.len().saturating_sub(k + 1))
+        {
+            let trailing = report.len() - 1 - i;
+            if let Some(r) = (0..=(k - trailing)).find(|r| ends[*r].is_some()) {
+                if best.is_none_or(|(_, removals)| r + trailing < removals) {
+                    best = Some((i, r + trailing));
+                }
+            }
+        }
+        let (mut last, removals) = best?;
+
+        // Walk back through the kept levels, recording everything in between
+        let mut removed: Vec<usize> = ((last + 1)..report.len()).collect();
+        let mut r = removals - removed.len();
+        loop {
+            match reachable[last][r].expect("Walked onto an unreachable level") {
+                Some(prev) => {
+                    removed.extend((prev + 1)..last);
+                    r -= last - prev - 1;
+                    last = prev;
+                }
+                None => {
+                    removed.extend(0..last);
+                    break;
+                }
+            }
+        }
+        removed.sort_unstable();
+        Some(removed)
+    }
+}
+
+fn parse_flag_value<T: std::str::FromStr>(flag: &str, value: Option<&String>) -> Result<T, String> {
+    value
+        .and_then(|v| v.parse::<T>().ok())
+        .ok_or_else(|| format!("{} needs a numeric value", flag))
+}
+
+pub fn process_input(path: &str, args: &[String]) {
+    // --explain is ours, everything else describes the policy
+    let explain = has_flag(args, "--explain");
+    let policy_args: Vec<String> = args
+        .iter()
+        .filter(|arg| *arg != "--explain")
+        .cloned()
+        .collect();
+    let policy = SafetyPolicy::from_args(SafetyPolicy::with_dampener(), &policy_args)
+        .expect("Couldn't parse day2 flags");
     let mut safe_reports = 0;
 
     // Read in the file, get the two vecs:
@@ -11,10 +301,16 @@ pub fn process_input(path: &str) {
         }
         let mut report = Vec::new();
         for value in line.split_whitespace().into_iter() {
-            report.push(value.parse::<u64>().expect("Couldn't parse to number"));
+            report.push(value.parse::<i64>().expect("Couldn't parse to number"));
         }
 
-        if is_safe_with_dampener(report) {
+        if explain {
+            let verdict = policy.check_report(&report);
+            println!("{}: {}", line, verdict);
+            if !matches!(verdict, Verdict::Unsafe { .. }) {
+                safe_reports += 1;
+            }
+        } else if policy.is_safe(&report) {
             safe_reports += 1;
         }
     }
@@ -22,73 +318,271 @@ pub fn process_input(path: &str) {
     println!("Safe: {}", safe_reports);
 }
 
-fn is_safe(mut report: Vec<u64>) -> bool {
-    assert!(report.len() > 1);
+/// Empty and single-level reports have no steps to break the rules, so they are always safe
+#[cfg(test)]
+fn is_safe(report: &[i64]) -> bool {
+    SafetyPolicy::default().is_safe(report)
+}
+
+#[cfg(test)]
+fn is_safe_with_dampener(report: &[i64]) -> bool {
+    SafetyPolicy::with_dampener().is_safe(report)
+}
 
-    let is_increasing = report[0] < report[1];
-    let diff = report[0].abs_diff(report[1]);
-    if diff < 1 || diff > 3 {
-        return false;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::XorShift;
+
+    #[test]
+    fn test_report_is_safe() {
+        assert_eq!(is_safe(&[7, 6, 4, 2, 1]), true);
+        assert_eq!(is_safe(&[1, 2, 7, 8, 9]), false);
+        assert_eq!(is_safe(&[9, 7, 6, 2, 1]), false);
+        assert_eq!(is_safe(&[1, 3, 2, 4, 5]), false);
+        assert_eq!(is_safe(&[8, 6, 4, 4, 1]), false);
+        assert_eq!(is_safe(&[1, 3, 6, 7, 9]), true);
     }
-    report.remove(0);
-    let mut prev = report[0];
-    report.remove(0);
-    for next in report.into_iter() {
-        if is_increasing && prev > next {
-            return false;
-        } else if !is_increasing && prev < next {
-            return false;
-        }
-        let diff = next.abs_diff(prev);
-        if diff < 1 || diff > 3 {
-            return false;
-        }
-        prev = next;
+
+    #[test]
+    fn test_short_reports_are_safe() {
+        assert!(is_safe(&[]));
+        assert!(is_safe(&[5]));
+        assert!(is_safe_with_dampener(&[5, 50]));
+        assert_eq!(SafetyPolicy::default().check_report(&[5]), Verdict::Safe);
     }
 
-    return true;
-}
+    #[test]
+    fn test_negative_levels() {
+        assert!(is_safe(&[-3, -1, 0, 2]));
+        assert!(!is_safe(&[-3, 1, 2]));
+        assert!(is_safe_with_dampener(&[-3, 1, -1, 2]));
+    }
 
-fn is_safe_with_dampener(mut report: Vec<u64>) -> bool {
-    // Is it safe _without_ modifications?
-    if is_safe(report.clone()) {
-        return true;
+    #[test]
+    fn test_report_is_safe_with_dampener() {
+        assert_eq!(is_safe_with_dampener(&[7, 6, 4, 2, 1]), true);
+        assert_eq!(is_safe_with_dampener(&[1, 2, 7, 8, 9]), false);
+        assert_eq!(is_safe_with_dampener(&[9, 7, 6, 2, 1]), false);
+        assert_eq!(is_safe_with_dampener(&[1, 3, 2, 4, 5]), true);
+        assert_eq!(is_safe_with_dampener(&[8, 6, 4, 4, 1]), true);
+        assert_eq!(is_safe_with_dampener(&[1, 3, 6, 7, 9]), true);
+        assert_eq!(is_safe_with_dampener(&[2, 1, 4, 5, 6]), true);
     }
 
-    for (i, _value) in report.iter().enumerate() {
-        // Is it safe without one of the numbers?
-        let mut modified_report = report.clone();
-        modified_report.remove(i);
-        if is_safe(modified_report) {
-            return true;
-        }
+    #[test]
+    fn test_policy_direction() {
+        let policy = SafetyPolicy {
+            direction: Direction::Increasing,
+            ..Default::default()
+        };
+        assert!(policy.is_safe(&[1, 3, 6, 7, 9]));
+        assert!(!policy.is_safe(&[7, 6, 4, 2, 1]));
+
+        let policy = SafetyPolicy {
+            direction: Direction::Decreasing,
+            ..Default::default()
+        };
+        assert!(!policy.is_safe(&[1, 3, 6, 7, 9]));
+        assert!(policy.is_safe(&[7, 6, 4, 2, 1]));
     }
 
-    return false;
-}
+    #[test]
+    fn test_policy_steps_and_plateaus() {
+        let policy = SafetyPolicy {
+            max_step: 5,
+            ..Default::default()
+        };
+        assert!(policy.is_safe(&[1, 2, 7, 8, 9]));
+        assert!(!policy.is_safe(&[8, 6, 4, 4, 1]));
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        let policy = SafetyPolicy {
+            allow_plateaus: true,
+            ..Default::default()
+        };
+        assert!(policy.is_safe(&[8, 6, 4, 4, 1]));
+        assert!(!policy.is_safe(&[1, 3, 2, 4, 5]));
+    }
 
     #[test]
-    fn test_report_is_safe() {
-        assert_eq!(is_safe(vec![7, 6, 4, 2, 1]), true);
-        assert_eq!(is_safe(vec![1, 2, 7, 8, 9]), false);
-        assert_eq!(is_safe(vec![9, 7, 6, 2, 1]), false);
-        assert_eq!(is_safe(vec![1, 3, 2, 4, 5]), false);
-        assert_eq!(is_safe(vec![8, 6, 4, 4, 1]), false);
-        assert_eq!(is_safe(vec![1, 3, 6, 7, 9]), true);
+    fn test_policy_multiple_removals() {
+        let policy = SafetyPolicy {
+            max_removals: 2,
+            ..Default::default()
+        };
+        assert!(!SafetyPolicy::with_dampener().is_safe(&[1, 9, 2, 9, 3]));
+        assert!(policy.is_safe(&[1, 9, 2, 9, 3]));
+        assert!(!policy.is_safe(&[1, 9, 2, 9, 3, 9, 4]));
+
+        // Allowing more removals than there are levels doesn't blow up
+        let policy = SafetyPolicy {
+            max_removals: usize::MAX,
+            ..Default::default()
+        };
+        assert!(policy.is_safe(&[1, 9, 2, 9, 3, 9, 4]));
+        assert_eq!(policy.find_removals(&[5, 5, 5]), Some(vec![1, 2]));
     }
 
     #[test]
-    fn test_report_is_safe_with_dampener() {
-        assert_eq!(is_safe_with_dampener(vec![7, 6, 4, 2, 1]), true);
-        assert_eq!(is_safe_with_dampener(vec![1, 2, 7, 8, 9]), false);
-        assert_eq!(is_safe_with_dampener(vec![9, 7, 6, 2, 1]), false);
-        assert_eq!(is_safe_with_dampener(vec![1, 3, 2, 4, 5]), true);
-        assert_eq!(is_safe_with_dampener(vec![8, 6, 4, 4, 1]), true);
-        assert_eq!(is_safe_with_dampener(vec![1, 3, 6, 7, 9]), true);
-        assert_eq!(is_safe_with_dampener(vec![2, 1, 4, 5, 6]), true);
+    fn test_policy_from_args() {
+        let args: Vec<String> = [
+            "--max-step",
+            "5",
+            "--direction",
+            "decreasing",
+            "--removals",
+            "2",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+        let policy = SafetyPolicy::from_args(SafetyPolicy::default(), &args).unwrap();
+        assert_eq!(policy.max_step, 5);
+        assert_eq!(policy.direction, Direction::Decreasing);
+        assert_eq!(policy.max_removals, 2);
+        assert!(
+            SafetyPolicy::from_args(SafetyPolicy::default(), &["--bogus".to_string()]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_check_report() {
+        let policy = SafetyPolicy::with_dampener();
+        assert_eq!(policy.check_report(&[7, 6, 4, 2, 1]), Verdict::Safe);
+        assert_eq!(
+            policy.check_report(&[1, 2, 7, 8, 9]),
+            Verdict::Unsafe {
+                violation: Violation {
+                    first: 1,
+                    second: 2,
+                    kind: ViolationKind::StepTooLarge
+                }
+            }
+        );
+        assert_eq!(
+            policy.check_report(&[1, 3, 2, 4, 5]),
+            Verdict::Dampened {
+                violation: Violation {
+                    first: 1,
+                    second: 2,
+                    kind: ViolationKind::DirectionChange
+                },
+                removed: vec![2]
+            }
+        );
+        assert_eq!(
+            policy.check_report(&[8, 6, 4, 4, 1]),
+            Verdict::Dampened {
+                violation: Violation {
+                    first: 2,
+                    second: 3,
+                    kind: ViolationKind::StepTooSmall
+                },
+                removed: vec![3]
+            }
+        );
+
+        let increasing = SafetyPolicy {
+            direction: Direction::Increasing,
+            ..Default::default()
+        };
+        assert_eq!(
+            increasing.first_violation(&[7, 6, 4, 2, 1]),
+            Some(Violation {
+                first: 0,
+                second: 1,
+                kind: ViolationKind::DirectionChange
+            })
+        );
+    }
+
+    /// The old approach: try every way of removing up to `removals` levels
+    fn is_safe_brute_force(policy: &SafetyPolicy, report: &[i64], removals: usize) -> bool {
+        let is_safe_undampened = report
+            .windows(2)
+            .all(|pair| policy.is_pair_allowed(Direction::Increasing, pair[0], pair[1]))
+            || report
+                .windows(2)
+                .all(|pair| policy.is_pair_allowed(Direction::Decreasing, pair[0], pair[1]));
+        let direction_ok = match policy.direction {
+            Direction::Increasing => report.windows(2).all(|pair| pair[0] <= pair[1]),
+            Direction::Decreasing => report.windows(2).all(|pair| pair[0] >= pair[1]),
+            Direction::Either => true,
+        };
+        if is_safe_undampened && direction_ok {
+            return true;
+        }
+        if removals == 0 {
+            return false;
+        }
+
+        (0..report.len()).any(|i| {
+            let mut modified_report = report.to_vec();
+            modified_report.remove(i);
+            is_safe_brute_force(policy, &modified_report, removals - 1)
+        })
+    }
+
+    #[test]
+    fn test_find_removals() {
+        let policy = SafetyPolicy::with_dampener();
+        assert_eq!(policy.find_removals(&[7, 6, 4, 2, 1]), Some(vec![]));
+        assert_eq!(policy.find_removals(&[1, 3, 2, 4, 5]), Some(vec![2]));
+        assert_eq!(policy.find_removals(&[8, 6, 4, 4, 1]), Some(vec![3]));
+        assert_eq!(policy.find_removals(&[2, 1, 4, 5, 6]), Some(vec![1]));
+        assert_eq!(policy.find_removals(&[1, 2, 7, 8, 9]), None);
+
+        let policy = SafetyPolicy {
+            max_removals: 2,
+            ..Default::default()
+        };
+        assert_eq!(policy.find_removals(&[1, 9, 2, 9, 3]), Some(vec![1, 3]));
+        assert_eq!(policy.find_removals(&[1, 2, 3, 9, 9]), Some(vec![3, 4]));
+    }
+
+    #[test]
+    fn test_fuzz_find_removals_against_brute_force() {
+        let mut rng = XorShift(0x2024_1202);
+        for _ in 0..5000 {
+            let policy = SafetyPolicy {
+                min_step: rng.next(2),
+                max_step: 1 + rng.next(4),
+                direction: [
+                    Direction::Increasing,
+                    Direction::Decreasing,
+                    Direction::Either,
+                ][rng.next(3) as usize],
+                allow_plateaus: rng.next(2) == 0,
+                max_removals: rng.next(4) as usize,
+            };
+            let len = rng.next(9) as usize;
+            let mut level = rng.next(10) as i64 - 5;
+            let report: Vec<i64> = (0..len)
+                .map(|_| {
+                    level += rng.next(9) as i64 - 4;
+                    level
+                })
+                .collect();
+
+            let expected = is_safe_brute_force(&policy, &report, policy.max_removals);
+            let removals = policy.find_removals(&report);
+            assert_eq!(removals.is_some(), expected, "{:?} {:?}", policy, report);
+
+            if let Some(removals) = removals {
+                assert!(removals.len() <= policy.max_removals);
+                let kept: Vec<i64> = report
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| !removals.contains(i))
+                    .map(|(_, level)| *level)
+                    .collect();
+                assert!(
+                    is_safe_brute_force(&policy, &kept, 0),
+                    "{:?} {:?}",
+                    policy,
+                    report
+                );
+            }
+        }
     }
 }
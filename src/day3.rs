@@ -1,49 +1,648 @@
-use std::fs::read_to_string;
+use std::{
+    collections::VecDeque,
+    fmt,
+    fs::{read_to_string, File},
+    io::{self, Read},
+    ops::Range,
+};
 
-use regex::Regex;
+use log::trace;
 
-pub fn process_input(path: &str) {
-    // Read in the file, get the two vecs:
-    let raw_input = read_to_string(path).expect("Couldn't open day3.txt");
-    println!("Result: {}", uncorrupt_with_donts(&raw_input));
+use crate::args::{flag_value, has_flag};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvalError {
+    Overflow,
+    DivisionByZero,
 }
 
-fn uncorrupt(input: &str) -> i64 {
-    let mut result = 0;
-    // Find multiplications:
-    let re = Regex::new(r"mul\(([0-9]+),([0-9]+)\)").expect("Couldn't create a regex");
+#[derive(Debug)]
+pub enum RunError {
+    Io(io::Error),
+    Eval(EvalError),
+}
 
-    for (_, [left, right]) in re.captures_iter(input).map(|c| c.extract()) {
-        let left = left.parse::<i64>().expect("Couldn't parse to number");
-        let right = right.parse::<i64>().expect("Couldn't parse to number");
-        println!("Going to mult {} * {} ", left, right);
-        result += left * right;
+impl From<io::Error> for RunError {
+    fn from(err: io::Error) -> Self {
+        RunError::Io(err)
     }
-    return result;
 }
 
-fn uncorrupt_with_donts(input: &str) -> i64 {
-    let mut result = 0;
-    // Split strings by don'ts, then split again on dos, process mults inside
-    for (i, line) in input.split("don't").enumerate() {
-        if i == 0 {
-            // We start enabled
-            result += uncorrupt(line);
-            continue;
-        }
-
-        // Split by dos:
-        for (j, inner) in line.split("do()").enumerate() {
-            if j == 0 {
-                // ignore, this is the don't half
+impl From<EvalError> for RunError {
+    fn from(err: EvalError) -> Self {
+        RunError::Eval(err)
+    }
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EvalError::Overflow => write!(f, "arithmetic overflow"),
+            EvalError::DivisionByZero => write!(f, "division by zero"),
+        }
+    }
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunError::Io(err) => write!(f, "couldn't read input: {}", err),
+            RunError::Eval(err) => write!(f, "couldn't evaluate instructions: {}", err),
+        }
+    }
+}
+
+pub type EvalFn = fn(&[i64]) -> Result<i64, EvalError>;
+
+#[derive(Clone, Copy)]
+pub enum Action {
+    /// Produces a value, which is added to the total while enabled
+    Value(EvalFn),
+    /// Turns counting on or off for the instructions that follow
+    SetEnabled(bool),
+}
+
+#[derive(Clone, Copy)]
+pub struct Instruction {
+    pub name: &'static str,
+    pub arity: usize,
+    pub action: Action,
+}
+
+/// The instructions the lexer recognises and the interpreter knows how to run
+#[derive(Clone, Default)]
+pub struct InstructionSet {
+    instructions: Vec<Instruction>,
+    /// Whether calls can be operands, like `mul(add(1,2),3)`
+    nested: bool,
+}
+
+impl InstructionSet {
+    /// `mul`, `do` and `don't`, as in the puzzle
+    pub fn standard() -> Self {
+        let mut set = InstructionSet::default();
+        set.register(
+            "mul",
+            2,
+            Action::Value(|args| args[0].checked_mul(args[1]).ok_or(EvalError::Overflow)),
+        );
+        set.register("do", 0, Action::SetEnabled(true));
+        set.register("don't", 0, Action::SetEnabled(false));
+        set
+    }
+
+    /// The standard set plus `add`, `sub` and `div`, which can be nested
+    pub fn extended() -> Self {
+        let mut set = InstructionSet::standard().with_nesting();
+        set.register(
+            "add",
+            2,
+            Action::Value(|args| args[0].checked_add(args[1]).ok_or(EvalError::Overflow)),
+        );
+        set.register(
+            "sub",
+            2,
+            Action::Value(|args| args[0].checked_sub(args[1]).ok_or(EvalError::Overflow)),
+        );
+        set.register(
+            "div",
+            2,
+            Action::Value(|args| {
+                if args[1] == 0 {
+                    return Err(EvalError::DivisionByZero);
+                }
+                args[0].checked_div(args[1]).ok_or(EvalError::Overflow)
+            }),
+        );
+        set
+    }
+
+    /// Lets calls that produce a value be operands of other calls
+    pub fn with_nesting(mut self) -> Self {
+        self.nested = true;
+        self
+    }
+
+    /// Adds an instruction, replacing any existing one with the same name
+    pub fn register(&mut self, name: &'static str, arity: usize, action: Action) {
+        self.instructions.retain(|i| i.name != name);
+        self.instructions.push(Instruction {
+            name,
+            arity,
+            action,
+        });
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Instruction> {
+        self.instructions.iter().find(|i| i.name == name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Arg {
+    Number(i64),
+    Call(Call),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Call {
+    pub name: &'static str,
+    pub args: Vec<Arg>,
+}
+
+impl fmt::Display for Call {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}(", self.name)?;
+        for (i, arg) in self.args.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            match arg {
+                Arg::Number(value) => write!(f, "{}", value)?,
+                Arg::Call(inner) => write!(f, "{}", inner)?,
+            }
+        }
+        write!(f, ")")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    /// An instruction and where it sits in the input
+    Call(Call, Range<usize>),
+    /// Bytes that aren't part of any instruction
+    Garbage(Range<usize>),
+}
+
+/// How much the lexer reads from its input at a time
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Longest instruction the lexer recognises, nested calls included. Nothing past this many bytes
+/// from where an instruction starts is looked at, which keeps memory use constant and bounds the
+/// work and nesting depth per position when nested calls never close.
+pub const MAX_INSTRUCTION_LEN: usize = 256;
+
+/// The lexer reached the end of what it has buffered before it could decide on a match
+struct Incomplete;
+
+/// Looks at byte `i` of the buffer, if it has been read yet
+fn peek(buffer: &[u8], i: usize, eof: bool) -> Result<Option<u8>, Incomplete> {
+    match buffer.get(i) {
+        Some(b) => Ok(Some(*b)),
+        None if eof => Ok(None),
+        None => Err(Incomplete),
+    }
+}
+
+/// Splits corrupted memory into instructions and the garbage between them. Input is read in
+/// fixed-size chunks, and a partial instruction at the end of a chunk is carried over to the next
+/// one, so arbitrarily large inputs can be scanned in constant memory.
+pub struct Lexer<'a, R> {
+    reader: R,
+    instructions: &'a InstructionSet,
+    chunk_size: usize,
+    /// Bytes read but not yet consumed
+    buffer: Vec<u8>,
+    /// Offset of `buffer[0]` in the whole input
+    offset: usize,
+    pos: usize,
+    eof: bool,
+    garbage_start: Option<usize>,
+    pending: VecDeque<Token>,
+}
+
+impl<'a> Lexer<'a, &'a [u8]> {
+    pub fn new(input: &'a str, instructions: &'a InstructionSet) -> Self {
+        Lexer::from_reader(input.as_bytes(), instructions, DEFAULT_CHUNK_SIZE)
+    }
+}
+
+impl<'a, R: Read> Lexer<'a, R> {
+    pub fn from_reader(reader: R, instructions: &'a InstructionSet, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0);
+        Lexer {
+            reader,
+            instructions,
+            chunk_size,
+            buffer: Vec::new(),
+            offset: 0,
+            pos: 0,
+            eof: false,
+            garbage_start: None,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Drops everything already consumed and reads the next chunk
+    fn fill(&mut self) -> io::Result<()> {
+        self.buffer.drain(..self.pos);
+        self.offset += self.pos;
+        self.pos = 0;
+
+        let len = self.buffer.len();
+        self.buffer.resize(len + self.chunk_size, 0);
+        let read = loop {
+            match self.reader.read(&mut self.buffer[len..]) {
+                Ok(read) => break read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    self.buffer.truncate(len);
+                    return Err(err);
+                }
+            }
+        };
+        self.buffer.truncate(len + read);
+        self.eof = read == 0;
+        Ok(())
+    }
+
+    fn flush_garbage(&mut self) {
+        if let Some(start) = self.garbage_start.take() {
+            self.pending
+                .push_back(Token::Garbage(start..self.offset + self.pos));
+        }
+    }
+
+    /// Looks at byte `i` of the buffer for an instruction starting at `start`. Bytes beyond
+    /// `MAX_INSTRUCTION_LEN` look like the end of the input, so nothing that long matches.
+    fn byte_within(&self, start: usize, i: usize) -> Result<Option<u8>, Incomplete> {
+        if i >= start + MAX_INSTRUCTION_LEN {
+            return Ok(None);
+        }
+        peek(&self.buffer, i, self.eof)
+    }
+
+    /// Tries to read a call starting at `pos`, returning it and its length. Nested calls are only
+    /// allowed if the instruction set says so, and have to produce a value, so `mul(do(),2)` isn't
+    /// an instruction. `start` is where the outermost call began.
+    fn call_at(
+        &self,
+        pos: usize,
+        nested: bool,
+        start: usize,
+    ) -> Result<Option<(Call, usize)>, Incomplete> {
+        if nested && !self.instructions.nested {
+            return Ok(None);
+        }
+        let mut incomplete = false;
+        for instruction in self.instructions.instructions.iter() {
+            if nested && !matches!(instruction.action, Action::Value(_)) {
                 continue;
             }
+            match self.instruction_at(instruction, pos, start) {
+                Ok(Some(found)) => return Ok(Some(found)),
+                Ok(None) => {}
+                Err(Incomplete) => incomplete = true,
+            }
+        }
+        if incomplete {
+            return Err(Incomplete);
+        }
+        Ok(None)
+    }
 
-            println!("Uncorrupting {}", inner);
-            result += uncorrupt(inner);
+    fn instruction_at(
+        &self,
+        instruction: &Instruction,
+        pos: usize,
+        start: usize,
+    ) -> Result<Option<(Call, usize)>, Incomplete> {
+        let byte = |i: usize| self.byte_within(start, pos + i);
+
+        for (i, expected) in instruction.name.bytes().enumerate() {
+            if byte(i)? != Some(expected) {
+                return Ok(None);
+            }
         }
+        let mut len = instruction.name.len();
+        if byte(len)? != Some(b'(') {
+            return Ok(None);
+        }
+        len += 1;
+
+        let mut args = Vec::new();
+        while args.len() < instruction.arity {
+            if !args.is_empty() {
+                if byte(len)? != Some(b',') {
+                    return Ok(None);
+                }
+                len += 1;
+            }
+            if let Some((value, digits)) = self.operand_at(pos + len, start)? {
+                args.push(Arg::Number(value));
+                len += digits;
+            } else if let Some((call, call_len)) = self.call_at(pos + len, true, start)? {
+                args.push(Arg::Call(call));
+                len += call_len;
+            } else {
+                return Ok(None);
+            }
+        }
+
+        if byte(len)? != Some(b')') {
+            return Ok(None);
+        }
+        let call = Call {
+            name: instruction.name,
+            args,
+        };
+        Ok(Some((call, len + 1)))
+    }
+
+    /// Reads a 1-3 digit number, returning it and how many bytes it took up
+    fn operand_at(&self, pos: usize, start: usize) -> Result<Option<(i64, usize)>, Incomplete> {
+        let mut value = 0;
+        let mut digits = 0;
+        while let Some(b) = self.byte_within(start, pos + digits)? {
+            if !b.is_ascii_digit() {
+                break;
+            }
+            digits += 1;
+            if digits > 3 {
+                return Ok(None);
+            }
+            value = value * 10 + i64::from(b - b'0');
+        }
+        if digits == 0 {
+            return Ok(None);
+        }
+        Ok(Some((value, digits)))
+    }
+}
+
+impl<R: Read> Iterator for Lexer<'_, R> {
+    type Item = io::Result<Token>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(token) = self.pending.pop_front() {
+                return Some(Ok(token));
+            }
+
+            if self.pos >= self.buffer.len() {
+                if self.eof {
+                    self.flush_garbage();
+                    return self.pending.pop_front().map(Ok);
+                }
+                if let Err(err) = self.fill() {
+                    return Some(Err(err));
+                }
+                continue;
+            }
+
+            let found = match self.call_at(self.pos, false, self.pos) {
+                Ok(found) => found,
+                Err(Incomplete) if self.buffer.len() - self.pos < MAX_INSTRUCTION_LEN => {
+                    // Might be an instruction split across chunks, read more before deciding
+                    if let Err(err) = self.fill() {
+                        return Some(Err(err));
+                    }
+                    continue;
+                }
+                Err(Incomplete) => None,
+            };
+
+            match found {
+                Some((call, len)) => {
+                    self.flush_garbage();
+                    let start = self.offset + self.pos;
+                    self.pending
+                        .push_back(Token::Call(call, start..start + len));
+                    self.pos += len;
+                }
+                None => {
+                    // Everything up to the next instruction is garbage
+                    self.garbage_start.get_or_insert(self.offset + self.pos);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+}
+
+/// What happened to a single instruction during a run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry {
+    /// Where the instruction is in the input
+    pub span: Range<usize>,
+    /// Whether the instruction had any effect
+    pub enabled: bool,
+    /// How much it added to the total
+    pub contribution: i64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trace {
+    pub entries: Vec<TraceEntry>,
+}
+
+impl Trace {
+    /// Renders the input with counted instructions in green, skipped ones in red, and
+    /// instructions that changed the enabled flag in yellow. Garbage is dimmed.
+    pub fn to_ansi(&self, input: &str) -> String {
+        const GREEN: &str = "\x1b[1;32m";
+        const RED: &str = "\x1b[31m";
+        const YELLOW: &str = "\x1b[1;33m";
+        const DIM: &str = "\x1b[2m";
+        const RESET: &str = "\x1b[0m";
+
+        let mut output = String::new();
+        let mut pos = 0;
+        for entry in self.entries.iter() {
+            let (start, end) = (entry.span.start, entry.span.end);
+            output.push_str(DIM);
+            output.push_str(&input[pos..start]);
+            let colour = match (entry.enabled, entry.contribution) {
+                (false, _) => RED,
+                (true, 0) => YELLOW,
+                (true, _) => GREEN,
+            };
+            output.push_str(colour);
+            output.push_str(&input[start..end]);
+            output.push_str(RESET);
+            pos = end;
+        }
+        output.push_str(DIM);
+        output.push_str(&input[pos..]);
+        output.push_str(RESET);
+        output
+    }
+
+    /// Lists the instructions as they appear in `input`
+    pub fn to_json(&self, input: &str) -> String {
+        let entries: Vec<String> = self
+            .entries
+            .iter()
+            .map(|entry| {
+                format!(
+                    "{{\"offset\":{},\"text\":\"{}\",\"enabled\":{},\"contribution\":{}}}",
+                    entry.span.start,
+                    input[entry.span.clone()]
+                        .replace('\\', "\\\\")
+                        .replace('"', "\\\""),
+                    entry.enabled,
+                    entry.contribution
+                )
+            })
+            .collect();
+        format!("[{}]", entries.join(","))
+    }
+}
+
+/// Runs a token stream, keeping track of whether values are currently enabled
+#[derive(Clone)]
+pub struct Interpreter<'a> {
+    instructions: &'a InstructionSet,
+    /// Whether `SetEnabled` instructions have any effect
+    pub conditionals: bool,
+    pub enabled: bool,
+    pub total: i64,
+    /// Filled in as instructions run, if enabled with `with_trace`
+    pub trace: Option<Trace>,
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new(instructions: &'a InstructionSet, conditionals: bool) -> Self {
+        Interpreter {
+            instructions,
+            conditionals,
+            enabled: true,
+            total: 0,
+            trace: None,
+        }
+    }
+
+    pub fn with_trace(mut self) -> Self {
+        self.trace = Some(Trace::default());
+        self
+    }
+
+    fn evaluate(&self, call: &Call) -> Result<i64, EvalError> {
+        let instruction = self
+            .instructions
+            .get(call.name)
+            .expect("Lexer produced an unknown instruction");
+        let args = call
+            .args
+            .iter()
+            .map(|arg| match arg {
+                Arg::Number(value) => Ok(*value),
+                Arg::Call(inner) => self.evaluate(inner),
+            })
+            .collect::<Result<Vec<i64>, EvalError>>()?;
+        match instruction.action {
+            Action::Value(eval) => eval(&args),
+            Action::SetEnabled(_) => unreachable!("Lexer never nests SetEnabled instructions"),
+        }
+    }
+
+    pub fn execute(&mut self, token: &Token) -> Result<(), EvalError> {
+        let (call, span) = match token {
+            Token::Call(call, span) => (call, span),
+            Token::Garbage(_) => return Ok(()),
+        };
+        let instruction = self
+            .instructions
+            .get(call.name)
+            .expect("Lexer produced an unknown instruction");
+        let (enabled, contribution) = match instruction.action {
+            Action::SetEnabled(enabled) if self.conditionals => {
+                self.enabled = enabled;
+                (true, 0)
+            }
+            Action::SetEnabled(_) => (false, 0),
+            Action::Value(_) if self.enabled => {
+                let value = self.evaluate(call)?;
+                self.total = self.total.checked_add(value).ok_or(EvalError::Overflow)?;
+                (true, value)
+            }
+            Action::Value(_) => (false, 0),
+        };
+
+        trace!(
+            "{} at {}: enabled {}, contributes {}",
+            call,
+            span.start,
+            enabled,
+            contribution
+        );
+        if let Some(trace) = self.trace.as_mut() {
+            trace.entries.push(TraceEntry {
+                span: span.clone(),
+                enabled,
+                contribution,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn run(&mut self, input: &str) -> Result<i64, EvalError> {
+        let instructions = self.instructions;
+        for token in Lexer::new(input, instructions) {
+            self.execute(&token.expect("Reading from memory can't fail"))?;
+        }
+        Ok(self.total)
+    }
+
+    /// Streams instructions from `reader`, `chunk_size` bytes at a time. The enabled flag and
+    /// total carry on from any earlier runs.
+    pub fn run_reader<R: Read>(&mut self, reader: R, chunk_size: usize) -> Result<i64, RunError> {
+        let instructions = self.instructions;
+        for token in Lexer::from_reader(reader, instructions, chunk_size) {
+            self.execute(&token?)?;
+        }
+        Ok(self.total)
+    }
+}
+
+pub fn process_input(path: &str, args: &[String]) {
+    let instructions = if has_flag(args, "--extended") {
+        InstructionSet::extended()
+    } else {
+        InstructionSet::standard()
+    };
+    let conditionals = !has_flag(args, "--part1");
+
+    let trace_format =
+        has_flag(args, "--trace").then(|| flag_value(args, "--trace").unwrap_or("ansi"));
+
+    let mut interpreter = Interpreter::new(&instructions, conditionals);
+    let result = match trace_format {
+        None => {
+            let file = File::open(path).expect("Couldn't open day3.txt");
+            interpreter.run_reader(file, DEFAULT_CHUNK_SIZE)
+        }
+        Some(format) => {
+            // The highlighted view needs the whole input, so tracing doesn't stream
+            let raw_input = read_to_string(path).expect("Couldn't open day3.txt");
+            interpreter = interpreter.with_trace();
+            let result = interpreter.run(&raw_input).map_err(RunError::Eval);
+            let trace = interpreter.trace.unwrap_or_default();
+            match format {
+                "json" => println!("{}", trace.to_json(&raw_input)),
+                _ => println!("{}", trace.to_ansi(&raw_input)),
+            }
+            result
+        }
+    };
+    match result {
+        Ok(total) => println!("Result: {}", total),
+        Err(err) => println!("Failed: {}", err),
     }
-    return result;
+}
+
+#[cfg(test)]
+fn uncorrupt(input: &str) -> i64 {
+    Interpreter::new(&InstructionSet::standard(), false)
+        .run(input)
+        .expect("Couldn't evaluate instructions")
+}
+
+#[cfg(test)]
+fn uncorrupt_with_donts(input: &str) -> i64 {
+    Interpreter::new(&InstructionSet::standard(), true)
+        .run(input)
+        .expect("Couldn't evaluate instructions")
 }
 
 #[cfg(test)]
@@ -67,4 +666,249 @@ mod tests {
             48
         );
     }
+
+    fn call(name: &'static str, args: Vec<Arg>, span: Range<usize>) -> Token {
+        Token::Call(Call { name, args }, span)
+    }
+
+    #[test]
+    fn test_lexer_tokens() {
+        let instructions = InstructionSet::standard();
+        let tokens: Vec<Token> = Lexer::new("xmul(2,4)don't()?do()mul(1,2]", &instructions)
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Garbage(0..1),
+                call("mul", vec![Arg::Number(2), Arg::Number(4)], 1..9),
+                call("don't", vec![], 9..16),
+                Token::Garbage(16..17),
+                call("do", vec![], 17..21),
+                Token::Garbage(21..29),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_operand_limits() {
+        assert_eq!(uncorrupt("mul(123,4)mul(1234,5)mul(6,7890)mul(,1)"), 492);
+    }
+
+    #[test]
+    fn test_standard_calls_dont_nest() {
+        // Only the inner mul is a real instruction
+        assert_eq!(uncorrupt("mul(mul(2,3),4)"), 6);
+        assert_eq!(uncorrupt("mul(2,mul(3,4))"), 12);
+        assert_eq!(
+            Interpreter::new(&InstructionSet::extended(), false).run("mul(mul(2,3),4)"),
+            Ok(24)
+        );
+    }
+
+    #[test]
+    fn test_dont_needs_parentheses() {
+        assert_eq!(
+            uncorrupt_with_donts("don't_mul(2,3)don't()mul(4,5)do()mul(1,1)"),
+            7
+        );
+    }
+
+    #[test]
+    fn test_extended_instructions() {
+        let instructions = InstructionSet::extended();
+        let mut interpreter = Interpreter::new(&instructions, true);
+        assert_eq!(
+            interpreter.run("add(1,2)sub(1,5)don't()div(9,3)do()mul(add(1,2),div(8,2))"),
+            Ok(11)
+        );
+        // Nested calls only work with instructions that are registered
+        assert_eq!(uncorrupt("mul(add(1,2),3)mul(2,2)"), 4);
+    }
+
+    #[test]
+    fn test_custom_instruction() {
+        let mut instructions = InstructionSet::standard().with_nesting();
+        instructions.register("neg", 1, Action::Value(|args| Ok(-args[0])));
+        let mut interpreter = Interpreter::new(&instructions, false);
+        assert_eq!(interpreter.run("neg(5)neg(1,2)mul(neg(2),3)"), Ok(-11));
+    }
+
+    #[test]
+    fn test_evaluation_errors() {
+        let instructions = InstructionSet::extended();
+        assert_eq!(
+            Interpreter::new(&instructions, false).run("div(1,0)"),
+            Err(EvalError::DivisionByZero)
+        );
+        assert_eq!(
+            Interpreter::new(&instructions, false)
+                .run("mul(mul(mul(mul(mul(mul(mul(999,999),999),999),999),999),999),999)"),
+            Err(EvalError::Overflow)
+        );
+    }
+
+    #[test]
+    fn test_chunk_boundaries() {
+        let input = "xmul(2,4)&mul[3,7]!^don't()_mul(5,5)+mul(32,64](mul(11,8)undo()?mul(8,5))";
+        let instructions = InstructionSet::extended();
+        let expected: Vec<Token> = Lexer::new(input, &instructions)
+            .collect::<io::Result<_>>()
+            .unwrap();
+
+        // Every chunk size splits some instruction in a different place
+        for chunk_size in 1..=16 {
+            let tokens: Vec<Token> =
+                Lexer::from_reader(input.as_bytes(), &instructions, chunk_size)
+                    .collect::<io::Result<_>>()
+                    .unwrap();
+            assert_eq!(tokens, expected, "chunk size {}", chunk_size);
+
+            let mut interpreter = Interpreter::new(&instructions, true);
+            let total = interpreter
+                .run_reader(input.as_bytes(), chunk_size)
+                .unwrap();
+            assert_eq!(total, 48);
+        }
+    }
+
+    #[test]
+    fn test_unclosed_prefix_is_bounded() {
+        // An endless chain of nested calls never becomes an instruction, so the lexer has to give up
+        // on it rather than buffer the whole input
+        let instructions = InstructionSet::extended();
+        let input = format!("{}mul(2,3)", "add(".repeat(MAX_INSTRUCTION_LEN));
+        let mut interpreter = Interpreter::new(&instructions, false);
+        assert_eq!(interpreter.run_reader(input.as_bytes(), 64).unwrap(), 6);
+    }
+
+    #[test]
+    fn test_unclosed_prefixes_stream_in_bounded_memory() {
+        // Several chunks of prefixes that never close. However long they go on, the lexer only
+        // ever holds one chunk plus the longest instruction it could still be in the middle of.
+        let instructions = InstructionSet::extended();
+        let chunk_size = 1024;
+        let mut input = String::new();
+        for prefix in ["mul(", "add(1,", "mul(add(", "don't("] {
+            input.push_str(&prefix.repeat(4 * chunk_size / prefix.len()));
+        }
+        input.push_str("mul(2,3)");
+
+        let mut lexer = Lexer::from_reader(input.as_bytes(), &instructions, chunk_size);
+        let mut buffered = 0;
+        let mut last = None;
+        while let Some(token) = lexer.next() {
+            buffered = buffered.max(lexer.buffer.len());
+            last = Some(token.unwrap());
+        }
+        assert!(
+            buffered <= chunk_size + MAX_INSTRUCTION_LEN,
+            "buffered {}",
+            buffered
+        );
+        assert_eq!(
+            last,
+            Some(call(
+                "mul",
+                vec![Arg::Number(2), Arg::Number(3)],
+                input.len() - 8..input.len()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_deep_nesting_is_capped() {
+        // Nested much deeper than an instruction can be long, so the lexer has to stop following
+        // the nesting after MAX_INSTRUCTION_LEN bytes
+        let instructions = InstructionSet::extended();
+        let input = format!("{}mul(2,3)", "add(".repeat(4096));
+        let mut interpreter = Interpreter::new(&instructions, false);
+        assert_eq!(interpreter.run(&input).unwrap(), 6);
+
+        for prefix in ["mul(", "mul(1,"] {
+            let input = format!("{}mul(2,3)", prefix.repeat(16 * 1024 / prefix.len()));
+            let mut interpreter = Interpreter::new(&instructions, false);
+            assert_eq!(interpreter.run(&input).unwrap(), 6);
+        }
+
+        // A nested call that fits is still found, one that's too long isn't
+        let nested = |depth: usize| {
+            format!(
+                "mul({}1,2){},5)",
+                "add(".repeat(depth),
+                ",0)".repeat(depth - 1)
+            )
+        };
+        let starts_with_call = |input: &str| {
+            matches!(
+                Lexer::new(input, &instructions).next(),
+                Some(Ok(Token::Call(_, span))) if span == (0..input.len())
+            )
+        };
+        let fits = nested(30);
+        assert!(fits.len() <= MAX_INSTRUCTION_LEN);
+        assert!(starts_with_call(&fits));
+        assert_eq!(
+            Interpreter::new(&instructions, false).run(&fits).unwrap(),
+            15
+        );
+        let too_long = nested(40);
+        assert!(too_long.len() > MAX_INSTRUCTION_LEN);
+        assert!(!starts_with_call(&too_long));
+    }
+
+    #[test]
+    fn test_trace() {
+        let input = "xmul(2,4)don't()_mul(5,5)do()?mul(add(1,2),3)";
+        let instructions = InstructionSet::extended();
+        let mut interpreter = Interpreter::new(&instructions, true).with_trace();
+        assert_eq!(interpreter.run(input), Ok(17));
+
+        let trace = interpreter.trace.unwrap();
+        let entry = |offset: usize, text: &str, enabled, contribution| TraceEntry {
+            span: offset..offset + text.len(),
+            enabled,
+            contribution,
+        };
+        assert_eq!(
+            trace.entries,
+            vec![
+                entry(1, "mul(2,4)", true, 8),
+                entry(9, "don't()", true, 0),
+                entry(17, "mul(5,5)", false, 0),
+                entry(25, "do()", true, 0),
+                entry(30, "mul(add(1,2),3)", true, 9),
+            ]
+        );
+
+        assert_eq!(
+            trace.to_json(input),
+            r#"[{"offset":1,"text":"mul(2,4)","enabled":true,"contribution":8},{"offset":9,"text":"don't()","enabled":true,"contribution":0},{"offset":17,"text":"mul(5,5)","enabled":false,"contribution":0},{"offset":25,"text":"do()","enabled":true,"contribution":0},{"offset":30,"text":"mul(add(1,2),3)","enabled":true,"contribution":9}]"#
+        );
+
+        let ansi = trace.to_ansi(input);
+        assert!(ansi.contains("\x1b[1;32mmul(2,4)\x1b[0m"));
+        assert!(ansi.contains("\x1b[31mmul(5,5)\x1b[0m"));
+        assert!(ansi.contains("\x1b[2m?"));
+    }
+
+    #[test]
+    fn test_trace_uses_input_text() {
+        // Leading zeros mean the call prints differently to how it was written
+        let input = "mul(002,3)xyz";
+        let instructions = InstructionSet::standard();
+        let mut interpreter = Interpreter::new(&instructions, true).with_trace();
+        assert_eq!(interpreter.run(input), Ok(6));
+
+        let trace = interpreter.trace.unwrap();
+        assert_eq!(trace.entries[0].span, 0..10);
+        assert_eq!(
+            trace.to_json(input),
+            r#"[{"offset":0,"text":"mul(002,3)","enabled":true,"contribution":6}]"#
+        );
+        assert_eq!(
+            trace.to_ansi(input),
+            "\x1b[2m\x1b[1;32mmul(002,3)\x1b[0m\x1b[2mxyz\x1b[0m"
+        );
+    }
 }
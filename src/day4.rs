@@ -1,7 +1,20 @@
-use std::{fs::read_to_string, str::FromStr};
+use std::{
+    collections::HashSet,
+    fmt,
+    fs::{read_to_string, write},
+    str::FromStr,
+};
+
+use aho_corasick::{AhoCorasick, AhoCorasickKind};
+use log::{debug, warn};
+
+use crate::{
+    args::{flag_value, has_flag},
+    rng::XorShift,
+};
 
 #[derive(Debug, Default, Clone)]
-struct Row {
+pub struct Row {
     pub data: Vec<char>,
 }
 
@@ -18,15 +31,30 @@ impl FromIterator<char> for Row {
     }
 }
 
+/// How the edges of the grid behave. On a wrapping edge, stepping off one side comes back in on
+/// the opposite side.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Topology {
+    #[default]
+    Bounded,
+    /// The left and right edges wrap around, top and bottom don't
+    HorizontalWrap,
+    /// Every edge wraps around
+    Torus,
+}
+
 #[derive(Debug, Default, Clone)]
-struct Puzzle {
+pub struct Puzzle {
     pub rows: Vec<Row>,
     pub height: usize,
     pub width: usize,
+    pub topology: Topology,
+    /// Cells that can't be part of any word
+    pub blocked: HashSet<(usize, usize)>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
-enum Direction {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Direction {
     UpLeft,
     Up,
     UpRight,
@@ -37,9 +65,125 @@ enum Direction {
     DownLeft,
 }
 
+impl Direction {
+    pub const ALL: [Direction; 8] = [
+        Direction::UpLeft,
+        Direction::Up,
+        Direction::UpRight,
+        Direction::Left,
+        Direction::Right,
+        Direction::DownLeft,
+        Direction::Down,
+        Direction::DownRight,
+    ];
+
+    pub fn is_diagonal(&self) -> bool {
+        matches!(
+            self,
+            Direction::UpLeft | Direction::UpRight | Direction::DownLeft | Direction::DownRight
+        )
+    }
+
+    pub fn opposite(&self) -> Direction {
+        match self {
+            Direction::UpLeft => Direction::DownRight,
+            Direction::Up => Direction::Down,
+            Direction::UpRight => Direction::DownLeft,
+            Direction::Left => Direction::Right,
+            Direction::Right => Direction::Left,
+            Direction::DownRight => Direction::UpLeft,
+            Direction::Down => Direction::Up,
+            Direction::DownLeft => Direction::UpRight,
+        }
+    }
+
+    pub fn offset(&self) -> (isize, isize) {
+        match self {
+            Direction::UpLeft => (-1, -1),
+            Direction::Up => (0, -1),
+            Direction::UpRight => (1, -1),
+            Direction::Left => (-1, 0),
+            Direction::Right => (1, 0),
+            Direction::DownRight => (1, 1),
+            Direction::Down => (0, 1),
+            Direction::DownLeft => (-1, 1),
+        }
+    }
+
+    /// Reversed directions read a word backwards compared to normal reading order (left to right,
+    /// top to bottom)
+    pub fn is_reversed(&self) -> bool {
+        matches!(
+            self,
+            Direction::UpLeft | Direction::Up | Direction::UpRight | Direction::Left
+        )
+    }
+}
+
+/// How `find_words` looks for matches. Both give the same results.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// Follows each word letter by letter from every cell
+    Recursive,
+    /// Cuts the grid into rows, columns and diagonals and runs a multi-pattern matcher over them
+    LineScan,
+}
+
+/// Which directions a word search looks in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SearchOptions {
+    pub reversed: bool,
+    pub diagonals: bool,
+    pub backend: Backend,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        SearchOptions {
+            reversed: true,
+            diagonals: true,
+            backend: Backend::LineScan,
+        }
+    }
+}
+
+impl SearchOptions {
+    pub fn directions(&self) -> Vec<Direction> {
+        Direction::ALL
+            .into_iter()
+            .filter(|dir| self.reversed || !dir.is_reversed())
+            .filter(|dir| self.diagonals || !dir.is_diagonal())
+            .collect()
+    }
+}
+
+/// A word found in the puzzle, starting at (`x`, `y`) and reading towards `direction`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct WordMatch {
+    pub word: String,
+    pub x: usize,
+    pub y: usize,
+    pub direction: Direction,
+}
+
+impl WordMatch {
+    /// The cells the word covers in the puzzle, in reading order
+    pub fn cells(&self, puzzle: &Puzzle) -> Vec<(usize, usize)> {
+        let mut cells = vec![(self.x, self.y)];
+        for _ in 1..self.word.chars().count() {
+            let (x, y) = cells[cells.len() - 1];
+            match puzzle.step(x, y, self.direction) {
+                Some(next) => cells.push(next),
+                None => break,
+            }
+        }
+        cells
+    }
+}
+
 impl Puzzle {
     fn at(&self, x: usize, y: usize) -> Option<&char> {
-        if x >= self.width && y >= self.height {
+        if x >= self.width || y >= self.height {
             return None;
         }
 
@@ -49,7 +193,81 @@ impl Puzzle {
         };
     }
 
+    /// The cell one step from (`x`, `y`) in `direction`, wrapping around edges as the topology
+    /// allows
+    pub fn step(&self, x: usize, y: usize, direction: Direction) -> Option<(usize, usize)> {
+        let (dx, dy) = direction.offset();
+        let (wrap_x, wrap_y) = self.wrapping();
+        let along = |pos: usize, delta: isize, size: usize, wrap: bool| {
+            let next = pos.checked_add_signed(delta);
+            match next {
+                Some(next) if next < size => Some(next),
+                _ if wrap && size > 0 => {
+                    Some((pos as isize + delta).rem_euclid(size as isize) as usize)
+                }
+                _ => None,
+            }
+        };
+        Some((
+            along(x, dx, self.width, wrap_x)?,
+            along(y, dy, self.height, wrap_y)?,
+        ))
+    }
+
+    /// The cell `dx` right and `dy` down from (`x`, `y`), wrapping around edges as the topology
+    /// allows
+    fn offset(&self, x: usize, y: usize, dx: usize, dy: usize) -> Option<(usize, usize)> {
+        let (wrap_x, wrap_y) = self.wrapping();
+        let along = |pos: usize, delta: usize, size: usize, wrap: bool| match pos + delta {
+            next if next < size => Some(next),
+            next if wrap && size > 0 => Some(next % size),
+            _ => None,
+        };
+        Some((
+            along(x, dx, self.width, wrap_x)?,
+            along(y, dy, self.height, wrap_y)?,
+        ))
+    }
+
+    /// Whether the horizontal and vertical edges wrap around
+    fn wrapping(&self) -> (bool, bool) {
+        match self.topology {
+            Topology::Bounded => (false, false),
+            Topology::HorizontalWrap => (true, false),
+            Topology::Torus => (true, true),
+        }
+    }
+
+    pub fn with_topology(mut self, topology: Topology) -> Self {
+        self.topology = topology;
+        self
+    }
+
+    pub fn block(&mut self, x: usize, y: usize) {
+        self.blocked.insert((x, y));
+    }
+
+    pub fn is_blocked(&self, x: usize, y: usize) -> bool {
+        self.blocked.contains(&(x, y))
+    }
+
+    /// Blocks every cell marked with `#` in `mask`, which is laid out like the puzzle
+    pub fn with_mask(mut self, mask: &str) -> Self {
+        for (y, line) in mask.lines().enumerate() {
+            for (x, cell) in line.chars().enumerate() {
+                if cell == '#' {
+                    self.block(x, y);
+                }
+            }
+        }
+        self
+    }
+
+    /// Blocked cells never match
     fn is_char_at(&self, x: usize, y: usize, needle: char) -> bool {
+        if self.is_blocked(x, y) {
+            return false;
+        }
         if let Some(letter) = self.at(x, y) {
             return *letter == needle;
         }
@@ -73,152 +291,1028 @@ impl Puzzle {
             return true;
         }
 
-        // Don't overflow with subtractions:
-        if x == 0
-            && (*direction == Direction::UpLeft
-                || *direction == Direction::Left
-                || *direction == Direction::DownLeft)
-        {
-            return false;
+        return match self.step(x, y, *direction) {
+            Some((x, y)) => self.is_chain(x, y, chain[1..].to_vec(), direction),
+            // Ran off the edge of the grid
+            None => false,
+        };
+    }
+}
+
+/// What to do with rows that are shorter or longer than the first one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RaggedRows {
+    Error,
+    /// Pad every row out to the longest one with this letter
+    Pad(char),
+}
+
+/// A row with a different number of letters than the first row. Lines are numbered from 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RaggedRowError {
+    pub line: usize,
+    pub expected: usize,
+    pub found: usize,
+}
+
+impl fmt::Display for RaggedRowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {} has {} letters, expected {}",
+            self.line, self.found, self.expected
+        )
+    }
+}
+
+impl Puzzle {
+    /// Reads a grid of letters. Widths are counted in chars, not bytes, so any alphabet works.
+    pub fn parse(s: &str, ragged: RaggedRows) -> Result<Puzzle, RaggedRowError> {
+        let mut rows: Vec<Row> = s.lines().map(|line| line.chars().collect()).collect();
+        let height = rows.len();
+        let width = match ragged {
+            RaggedRows::Error => {
+                let width = rows.first().map(|row| row.data.len()).unwrap_or(0);
+                for (i, row) in rows.iter().enumerate() {
+                    if row.data.len() != width {
+                        return Err(RaggedRowError {
+                            line: i + 1,
+                            expected: width,
+                            found: row.data.len(),
+                        });
+                    }
+                }
+                width
+            }
+            RaggedRows::Pad(letter) => {
+                let width = rows.iter().map(|row| row.data.len()).max().unwrap_or(0);
+                for row in rows.iter_mut() {
+                    row.data.resize(width, letter);
+                }
+                width
+            }
+        };
+
+        Ok(Puzzle {
+            rows,
+            height,
+            width,
+            ..Default::default()
+        })
+    }
+}
+
+impl FromStr for Puzzle {
+    type Err = RaggedRowError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Puzzle::parse(s, RaggedRows::Error)
+    }
+}
+
+/// Flags: `--words A,B,...` searches for the given words instead of counting XMASes and X-MASes,
+/// `--no-reversed` / `--no-diagonals` limit the directions searched, and `--recursive` uses the
+/// letter-by-letter search. `--render plain|ansi|html` shows where the words, or XMAS without
+/// `--words`, were found (HTML goes to `--output`, `day4.html` by default), and `--overlaps`
+/// colours letters shared by several words differently. `--topology bounded|hwrap|torus` picks
+/// how the edges behave, and `--mask` names a file laid out like the puzzle where `#` blocks a
+/// cell. `--pad C` fills out rows that are too short with `C` instead of rejecting the puzzle.
+///
+/// `--generate WIDTHxHEIGHT` writes a random puzzle to `--output` (`day4-generated.txt` by
+/// default) instead, with `--plant N` XMASes reading in the directions allowed above and
+/// `--crosses N` X-MASes. `--seed N` picks the puzzle and `--no-overlaps` keeps answers apart.
+pub fn process_input(path: &str, args: &[String]) {
+    if let Some(size) = flag_value(args, "--generate") {
+        write_generated(size, args);
+        return;
+    }
+
+    // Read in the file, get the two vecs:
+    let raw_input = read_to_string(path).expect("Couldn't open day4.txt");
+    let topology = match flag_value(args, "--topology") {
+        None | Some("bounded") => Topology::Bounded,
+        Some("hwrap") => Topology::HorizontalWrap,
+        Some("torus") => Topology::Torus,
+        Some(other) => panic!("Unknown topology: {}", other),
+    };
+    let ragged = match flag_value(args, "--pad") {
+        Some(pad) => {
+            let mut chars = pad.chars();
+            match (chars.next(), chars.next()) {
+                (Some(letter), None) => RaggedRows::Pad(letter),
+                _ => panic!("--pad takes a single character, got {}", pad),
+            }
         }
+        None => RaggedRows::Error,
+    };
+    let mut puzzle = Puzzle::parse(&raw_input, ragged)
+        .unwrap_or_else(|err| panic!("Couldn't parse the puzzle: {}", err))
+        .with_topology(topology);
+    if let Some(mask_path) = flag_value(args, "--mask") {
+        let mask = read_to_string(mask_path).expect("Couldn't open the mask");
+        puzzle = puzzle.with_mask(&mask);
+    }
 
-        if y == 0
-            && (*direction == Direction::UpLeft
-                || *direction == Direction::Up
-                || *direction == Direction::UpRight)
-        {
-            return false;
+    let render = flag_value(args, "--render").map(|style| match style {
+        "plain" => RenderStyle::Plain,
+        "ansi" => RenderStyle::Ansi,
+        "html" => RenderStyle::Html,
+        other => panic!("Unknown render style: {}", other),
+    });
+    let words = match flag_value(args, "--words") {
+        Some(words) => words,
+        None if render.is_some() => "XMAS",
+        None => {
+            println!("XMAS: {}", find_xmas(puzzle.clone()));
+            println!("X-mases: {}", find_mases(puzzle));
+            return;
         }
+    };
+
+    let options = search_options(args);
+    let words: Vec<&str> = words.split(',').collect();
+    let matches = find_words(&puzzle, &words, &options);
+    for word in words.iter() {
+        let count = matches.iter().filter(|m| m.word == *word).count();
+        println!("{}: {}", word, count);
+    }
 
-        return match direction {
-            Direction::UpLeft => self.is_chain(x - 1, y - 1, chain[1..].to_vec(), direction),
-            Direction::Up => self.is_chain(x, y - 1, chain[1..].to_vec(), direction),
-            Direction::UpRight => self.is_chain(x + 1, y - 1, chain[1..].to_vec(), direction),
-            Direction::Left => self.is_chain(x - 1, y, chain[1..].to_vec(), direction),
-            Direction::Right => self.is_chain(x + 1, y, chain[1..].to_vec(), direction),
-            Direction::DownRight => self.is_chain(x + 1, y + 1, chain[1..].to_vec(), direction),
-            Direction::Down => self.is_chain(x, y + 1, chain[1..].to_vec(), direction),
-            Direction::DownLeft => self.is_chain(x - 1, y + 1, chain[1..].to_vec(), direction),
+    let overlaps = has_flag(args, "--overlaps");
+    match render {
+        Some(RenderStyle::Html) => {
+            let output = flag_value(args, "--output").unwrap_or("day4.html");
+            let html = render_matches(&puzzle, &matches, RenderStyle::Html, overlaps);
+            write(output, html).expect("Couldn't write the HTML file");
+            println!("Wrote {}", output);
+        }
+        Some(style) => print!("{}", render_matches(&puzzle, &matches, style, overlaps)),
+        None => {}
+    }
+}
+
+fn search_options(args: &[String]) -> SearchOptions {
+    SearchOptions {
+        reversed: !has_flag(args, "--no-reversed"),
+        diagonals: !has_flag(args, "--no-diagonals"),
+        backend: if has_flag(args, "--recursive") {
+            Backend::Recursive
+        } else {
+            Backend::LineScan
+        },
+    }
+}
+
+fn write_generated(size: &str, args: &[String]) {
+    let (width, height) = size
+        .split_once('x')
+        .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+        .expect("Couldn't parse the size, expected WIDTHxHEIGHT");
+    let defaults = GeneratorOptions::default();
+    let number = |flag: &str, default: u64| {
+        flag_value(args, flag)
+            .map(|value| value.parse().expect("Couldn't parse number"))
+            .unwrap_or(default)
+    };
+    let options = GeneratorOptions {
+        width,
+        height,
+        words: number("--plant", defaults.words as u64) as usize,
+        directions: search_options(args).directions(),
+        crosses: number("--crosses", defaults.crosses as u64) as usize,
+        overlaps: !has_flag(args, "--no-overlaps"),
+        seed: number("--seed", defaults.seed),
+    };
+
+    let generated = generate_puzzle(&options);
+    let output = flag_value(args, "--output").unwrap_or("day4-generated.txt");
+    write(output, generated.input()).expect("Couldn't write the puzzle");
+    println!("Wrote {}", output);
+    println!("XMAS: {}", generated.expected_xmas());
+    println!("X-mases: {}", generated.expected_mases());
+}
+
+/// Finds every occurrence of every word, in all of the directions `options` allows. Matches are
+/// ordered by word, then position, then direction.
+pub fn find_words(puzzle: &Puzzle, words: &[&str], options: &SearchOptions) -> Vec<WordMatch> {
+    let mut matches = match options.backend {
+        Backend::Recursive => find_words_recursive(puzzle, words, options),
+        Backend::LineScan => find_words_line_scan(puzzle, words, options),
+    };
+    matches.sort_by_key(|m| {
+        let word = words.iter().position(|w| *w == m.word);
+        (word, m.y, m.x, m.direction)
+    });
+    matches
+}
+
+fn find_words_recursive(
+    puzzle: &Puzzle,
+    words: &[&str],
+    options: &SearchOptions,
+) -> Vec<WordMatch> {
+    let directions = options.directions();
+    let mut matches = Vec::new();
+
+    for word in words.iter() {
+        let chain: Vec<char> = word.chars().collect();
+        let Some(first) = chain.first() else {
+            continue;
+        };
+        for y in 0..puzzle.height {
+            for x in 0..puzzle.width {
+                if !puzzle.is_char_at(x, y, *first) {
+                    continue;
+                }
+                // Look for chains
+                for dir in directions.iter() {
+                    if puzzle.is_chain(x, y, chain.clone(), dir) {
+                        debug!("Found {} at ({}, {}) in Dir: {:?}", word, x, y, dir);
+                        matches.push(WordMatch {
+                            word: word.to_string(),
+                            x,
+                            y,
+                            direction: *dir,
+                        });
+                    }
+                }
+            }
+        }
+    }
+    matches
+}
+
+/// A straight run of cells through the grid, read in `direction` from `start`
+struct Line {
+    direction: Direction,
+    start: (usize, usize),
+    text: String,
+    /// Widths and heights to wrap around at, if any
+    wrap: (Option<usize>, Option<usize>),
+    /// For lines that loop back on themselves, how many cells are in the loop. `text` then repeats
+    /// the start of the loop, so words crossing the seam are found too.
+    cycle: Option<usize>,
+}
+
+impl Line {
+    /// The cell `index` chars along the line
+    fn cell(&self, index: usize) -> (usize, usize) {
+        let (dx, dy) = self.direction.offset();
+        let along = |start: usize, delta: isize, wrap: Option<usize>| {
+            let pos = start as isize + delta * index as isize;
+            match wrap {
+                Some(size) => pos.rem_euclid(size as isize) as usize,
+                None => pos as usize,
+            }
         };
+        (
+            along(self.start.0, dx, self.wrap.0),
+            along(self.start.1, dy, self.wrap.1),
+        )
     }
 
-    fn is_x_mas(&self, x: usize, y: usize) -> bool {
-        if x >= self.width || y >= self.height {
-            return false;
+    /// How many chars into `text` byte `offset` is
+    fn char_index(&self, offset: usize, ascii: bool) -> usize {
+        if ascii {
+            return offset;
         }
+        self.text[..offset].chars().count()
+    }
+}
 
-        // Don't underflow from subtraction:
-        if x == 0 || y == 0 {
-            return false;
+impl Puzzle {
+    /// Every row, column and (optionally) diagonal, read in the forward directions. Lines that
+    /// wrap around are long enough to hold words of up to `max_len` chars.
+    fn lines(&self, diagonals: bool, max_len: usize) -> Vec<Line> {
+        match self.topology {
+            Topology::Bounded => self.bounded_lines(diagonals),
+            _ => self.wrapped_lines(diagonals, max_len),
         }
+    }
 
-        // Check UpLeft and DownRight for M and S
-        let up_left = self.at(x - 1, y - 1).unwrap_or(&'!');
-        let down_right = self.at(x + 1, y + 1).unwrap_or(&'!');
+    /// The grid is walked once row by row, appending each cell to the lines running through it. A
+    /// line is split wherever a row is too short to have a cell.
+    fn bounded_lines(&self, diagonals: bool) -> Vec<Line> {
+        // The lines still being built for each direction, indexed by which line a cell is on
+        let open = |count: usize| -> Vec<Option<Line>> { (0..count).map(|_| None).collect() };
+        let diagonal_count = self.width + self.height;
+        let mut families = vec![
+            (Direction::Right, open(self.height)),
+            (Direction::Down, open(self.width)),
+        ];
+        if diagonals {
+            families.push((Direction::DownRight, open(diagonal_count)));
+            families.push((Direction::DownLeft, open(diagonal_count)));
+        }
+        let mut lines = Vec::new();
 
-        if *up_left == 'M' && *down_right == 'S' {
-            // Good
-        } else if *up_left == 'S' && *down_right == 'M' {
-            // Good
-        } else {
-            return false;
+        for (y, row) in self.rows.iter().enumerate() {
+            for x in 0..self.width {
+                let letter = row.data.get(x);
+                for (direction, open) in families.iter_mut() {
+                    let key = match direction {
+                        Direction::Right => y,
+                        Direction::Down => x,
+                        Direction::DownRight => x + self.height - y,
+                        _ => x + y,
+                    };
+                    match (letter, open[key].as_mut()) {
+                        (Some(letter), Some(line)) => line.text.push(*letter),
+                        (Some(letter), None) => {
+                            open[key] = Some(Line {
+                                direction: *direction,
+                                start: (x, y),
+                                text: letter.to_string(),
+                                wrap: (None, None),
+                                cycle: None,
+                            })
+                        }
+                        (None, _) => lines.extend(open[key].take()),
+                    }
+                }
+            }
         }
 
-        // Check UpRight and DownLeft for M and S
-        let up_right = self.at(x + 1, y - 1).unwrap_or(&'!');
-        let down_left = self.at(x - 1, y + 1).unwrap_or(&'!');
+        for (_, open) in families {
+            lines.extend(open.into_iter().flatten());
+        }
+        lines
+    }
 
-        if *up_right == 'M' && *down_left == 'S' {
-            // Good
-        } else if *up_right == 'S' && *down_left == 'M' {
-            // Good
-        } else {
-            return false;
+    /// Follows each direction from every cell that can't be reached by stepping forwards, then
+    /// picks up whatever is left, which can only be loops
+    fn wrapped_lines(&self, diagonals: bool, max_len: usize) -> Vec<Line> {
+        let (wrap_x, wrap_y) = self.wrapping();
+        let wrap = (wrap_x.then_some(self.width), wrap_y.then_some(self.height));
+        let cells: Vec<(usize, usize)> = (0..self.height)
+            .flat_map(|y| (0..self.width).map(move |x| (x, y)))
+            .collect();
+        let mut directions = vec![Direction::Right, Direction::Down];
+        if diagonals {
+            directions.extend([Direction::DownRight, Direction::DownLeft]);
         }
+        let mut lines = Vec::new();
+
+        for direction in directions {
+            let mut visited = vec![vec![false; self.width]; self.height];
+            let starts = cells
+                .iter()
+                .filter(|(x, y)| self.step(*x, *y, direction.opposite()).is_none());
+            for start in starts.chain(cells.iter()) {
+                if visited[start.1][start.0] {
+                    continue;
+                }
+                // Missing cells in a ragged grid can't be part of a word
+                let mut letters = Vec::new();
+                let mut cell = Some(*start);
+                while let Some((x, y)) = cell {
+                    if visited[y][x] {
+                        break;
+                    }
+                    visited[y][x] = true;
+                    letters.push(self.at(x, y).copied().unwrap_or('\0'));
+                    cell = self.step(x, y, direction);
+                }
 
-        return true;
+                let cycle = (cell == Some(*start)).then_some(letters.len());
+                if let Some(len) = cycle {
+                    for i in 0..max_len.saturating_sub(1) {
+                        letters.push(letters[i % len]);
+                    }
+                }
+                lines.push(Line {
+                    direction,
+                    start: *start,
+                    text: letters.into_iter().collect(),
+                    wrap,
+                    cycle,
+                });
+            }
+        }
+        lines
     }
 }
 
-impl FromStr for Puzzle {
-    type Err = bool;
+fn find_words_line_scan(
+    puzzle: &Puzzle,
+    words: &[&str],
+    options: &SearchOptions,
+) -> Vec<WordMatch> {
+    // Each distinct pattern remembers which words it stands for, and whether it's reversed
+    let mut patterns: Vec<String> = Vec::new();
+    let mut pattern_words: Vec<Vec<(&str, bool)>> = Vec::new();
+    for word in words.iter().filter(|word| !word.is_empty()) {
+        let mut variants = vec![(word.to_string(), false)];
+        if options.reversed {
+            variants.push((word.chars().rev().collect(), true));
+        }
+        for (pattern, reversed) in variants {
+            match patterns.iter().position(|p| *p == pattern) {
+                Some(i) => pattern_words[i].push((word, reversed)),
+                None => {
+                    patterns.push(pattern);
+                    pattern_words.push(vec![(word, reversed)]);
+                }
+            }
+        }
+    }
+    if patterns.is_empty() {
+        return Vec::new();
+    }
+    let matcher = AhoCorasick::builder()
+        .kind(Some(AhoCorasickKind::DFA))
+        .build(&patterns)
+        .expect("Couldn't build the word matcher");
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut rows: Vec<Row> = Vec::new();
-        let mut height = 0;
-        let mut width = 0;
-        for line in s.lines().into_iter() {
-            height += 1;
-            width = line.len();
-            rows.push(line.chars().collect());
-        }
-
-        return Ok(Puzzle {
-            rows: rows,
-            height: height,
-            width: width,
+    let max_len = words
+        .iter()
+        .map(|word| word.chars().count())
+        .max()
+        .unwrap_or(0);
+    let mut matches = Vec::new();
+    for line in puzzle.lines(options.diagonals, max_len) {
+        let ascii = line.text.is_ascii();
+        for found in matcher.find_overlapping_iter(&line.text) {
+            let start = line.char_index(found.start(), ascii);
+            if line.cycle.is_some_and(|len| start >= len) {
+                // Already found starting from the first time around the loop
+                continue;
+            }
+            for (word, reversed) in pattern_words[found.pattern().as_usize()].iter() {
+                // A reversed match starts at the far end of the line segment and reads back
+                let ((x, y), direction) = if *reversed {
+                    let end = start + word.chars().count() - 1;
+                    (line.cell(end), line.direction.opposite())
+                } else {
+                    (line.cell(start), line.direction)
+                };
+                debug!("Found {} at ({}, {}) in Dir: {:?}", word, x, y, direction);
+                matches.push(WordMatch {
+                    word: word.to_string(),
+                    x,
+                    y,
+                    direction,
+                });
+            }
+        }
+    }
+
+    if !puzzle.blocked.is_empty() {
+        matches.retain(|m| {
+            m.cells(puzzle)
+                .iter()
+                .all(|(x, y)| !puzzle.is_blocked(*x, *y))
         });
     }
+    matches
 }
 
-pub fn process_input(path: &str) {
-    // Read in the file, get the two vecs:
-    let raw_input = read_to_string(path).expect("Couldn't open day4.txt");
-    let puzzle = Puzzle::from_str(&raw_input).expect("Couldn't parse from string");
-    println!("X-mases: {}", find_mases(puzzle));
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenderStyle {
+    Plain,
+    Ansi,
+    Html,
 }
 
-pub fn find_xmas(puzzle: Puzzle) -> u64 {
-    let mut xmases = 0;
+/// Draws the puzzle with only the letters that are part of a match, and `.` everywhere else. With
+/// `overlaps`, letters shared by more than one match get their own colour.
+pub fn render_matches(
+    puzzle: &Puzzle,
+    matches: &[WordMatch],
+    style: RenderStyle,
+    overlaps: bool,
+) -> String {
+    let mut hits = vec![vec![0; puzzle.width]; puzzle.height];
+    for cell in matches.iter().flat_map(|m| m.cells(puzzle)) {
+        if let Some(count) = hits.get_mut(cell.1).and_then(|row| row.get_mut(cell.0)) {
+            *count += 1;
+        }
+    }
 
-    for y in 0..puzzle.height {
-        for x in 0..puzzle.width {
-            if puzzle.is_char_at(x, y, 'X') {
-                // Look for chains
-                let directions = vec![
-                    Direction::UpLeft,
-                    Direction::Up,
-                    Direction::UpRight,
-                    Direction::Left,
-                    Direction::Right,
-                    Direction::DownLeft,
-                    Direction::Down,
-                    Direction::DownRight,
-                ];
-                for dir in directions.into_iter() {
-                    if puzzle.is_chain(x, y, vec!['X', 'M', 'A', 'S'], &dir) {
-                        println!("Found XMAS at ({}, {}) in Dir: {:?}", x, y, dir);
-                        xmases += 1;
-                    }
+    let mut output = String::new();
+    if style == RenderStyle::Html {
+        output.push_str("<!DOCTYPE html>\n<html>\n<head>\n<style>\n");
+        output.push_str("pre { font-family: monospace; color: #999; }\n");
+        output.push_str(".hit { color: #2a2; font-weight: bold; }\n");
+        output.push_str(".overlap { color: #d80; font-weight: bold; }\n");
+        output.push_str("</style>\n</head>\n<body>\n<pre>\n");
+    }
+    for (y, row) in hits.iter().enumerate() {
+        for (x, count) in row.iter().enumerate() {
+            let letter = match puzzle.at(x, y) {
+                Some(letter) if *count > 0 => *letter,
+                _ => {
+                    output.push('.');
+                    continue;
+                }
+            };
+            let overlap = overlaps && *count > 1;
+            match style {
+                RenderStyle::Plain => output.push(letter),
+                RenderStyle::Ansi => {
+                    let colour = if overlap { "\x1b[1;33m" } else { "\x1b[1;32m" };
+                    output.push_str(&format!("{}{}\x1b[0m", colour, letter));
+                }
+                RenderStyle::Html => {
+                    let class = if overlap { "overlap" } else { "hit" };
+                    let letter = match letter {
+                        '<' => "&lt;".to_string(),
+                        '>' => "&gt;".to_string(),
+                        '&' => "&amp;".to_string(),
+                        _ => letter.to_string(),
+                    };
+                    output.push_str(&format!("<span class=\"{}\">{}</span>", class, letter));
                 }
             }
         }
+        output.push('\n');
+    }
+    if style == RenderStyle::Html {
+        output.push_str("</pre>\n</body>\n</html>\n");
     }
-    return xmases;
+    output
+}
+
+pub fn find_xmas(puzzle: Puzzle) -> u64 {
+    find_words(&puzzle, &["XMAS"], &SearchOptions::default()).len() as u64
 }
 
+/// Two MASes crossing in an X, in any orientation
+pub const X_MAS: &str = "\
+M.S
+.A.
+M.S
+";
+
 pub fn find_mases(puzzle: Puzzle) -> u64 {
-    let mut mases = 0;
-    for y in 0..puzzle.height {
-        for x in 0..puzzle.width {
-            if puzzle.is_char_at(x, y, 'A') {
-                // Look for MAS
-                if puzzle.is_x_mas(x, y) {
-                    mases += 1;
+    let pattern = Pattern::from_str(X_MAS).expect("Couldn't parse pattern");
+    find_pattern(&puzzle, &pattern, true).len() as u64
+}
+
+/// A small grid of letters to look for, where `.` matches anything
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    cells: Vec<Vec<Option<char>>>,
+    width: usize,
+    height: usize,
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let cells: Vec<Vec<Option<char>>> = s
+            .lines()
+            .map(|line| {
+                line.chars()
+                    .map(|c| if c == '.' { None } else { Some(c) })
+                    .collect()
+            })
+            .collect();
+        let width = cells.first().map(|row| row.len()).unwrap_or(0);
+        if width == 0 || cells.iter().any(|row| row.len() != width) {
+            return Err("Pattern must be a non-empty rectangle".to_string());
+        }
+        Ok(Pattern {
+            height: cells.len(),
+            width,
+            cells,
+        })
+    }
+}
+
+/// How a pattern was turned to produce a match: `rotation` quarter turns clockwise, after being
+/// mirrored left to right if `reflected`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Transform {
+    pub rotation: u8,
+    pub reflected: bool,
+}
+
+/// Where a pattern matched, by the top left corner of its (transformed) bounding box
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PatternMatch {
+    pub x: usize,
+    pub y: usize,
+    pub transform: Transform,
+}
+
+impl Pattern {
+    /// Turns the pattern a quarter turn clockwise
+    pub fn rotate(&self) -> Pattern {
+        let cells = (0..self.width)
+            .map(|x| (0..self.height).rev().map(|y| self.cells[y][x]).collect())
+            .collect();
+        Pattern {
+            cells,
+            width: self.height,
+            height: self.width,
+        }
+    }
+
+    /// Mirrors the pattern left to right
+    pub fn reflect(&self) -> Pattern {
+        let cells = self
+            .cells
+            .iter()
+            .map(|row| row.iter().rev().copied().collect())
+            .collect();
+        Pattern {
+            cells,
+            width: self.width,
+            height: self.height,
+        }
+    }
+
+    /// Every distinct way the pattern can be rotated and reflected. Symmetric patterns have fewer
+    /// than 8, so they aren't counted more than once at the same spot.
+    pub fn variants(&self) -> Vec<(Transform, Pattern)> {
+        let mut variants: Vec<(Transform, Pattern)> = Vec::new();
+        for reflected in [false, true] {
+            let mut pattern = if reflected {
+                self.reflect()
+            } else {
+                self.clone()
+            };
+            for rotation in 0..4 {
+                if !variants.iter().any(|(_, seen)| *seen == pattern) {
+                    variants.push((
+                        Transform {
+                            rotation,
+                            reflected,
+                        },
+                        pattern.clone(),
+                    ));
+                }
+                pattern = pattern.rotate();
+            }
+        }
+        variants
+    }
+
+    fn matches_at(&self, puzzle: &Puzzle, x: usize, y: usize) -> bool {
+        self.cells.iter().enumerate().all(|(dy, row)| {
+            row.iter().enumerate().all(|(dx, cell)| match cell {
+                Some(letter) => puzzle
+                    .offset(x, y, dx, dy)
+                    .is_some_and(|(x, y)| puzzle.is_char_at(x, y, *letter)),
+                None => true,
+            })
+        })
+    }
+}
+
+/// Finds every place the pattern fits in the puzzle. With `symmetries`, every rotation and
+/// reflection of the pattern is tried as well.
+pub fn find_pattern(puzzle: &Puzzle, pattern: &Pattern, symmetries: bool) -> Vec<PatternMatch> {
+    let variants = if symmetries {
+        pattern.variants()
+    } else {
+        vec![(
+            Transform {
+                rotation: 0,
+                reflected: false,
+            },
+            pattern.clone(),
+        )]
+    };
+
+    // On a wrapping axis the pattern can start anywhere and carry on round the edge
+    let (wrap_x, wrap_y) = puzzle.wrapping();
+    let origins = |size: usize, extent: usize, wrap: bool| {
+        if wrap {
+            size
+        } else {
+            (size + 1).saturating_sub(extent)
+        }
+    };
+    let mut matches = Vec::new();
+    for (transform, variant) in variants.iter() {
+        for y in 0..origins(puzzle.height, variant.height, wrap_y) {
+            for x in 0..origins(puzzle.width, variant.width, wrap_x) {
+                if variant.matches_at(puzzle, x, y) {
+                    matches.push(PatternMatch {
+                        x,
+                        y,
+                        transform: *transform,
+                    });
+                }
+            }
+        }
+    }
+    matches.sort();
+    matches
+}
+
+/// What to put in a generated puzzle
+#[derive(Debug, Clone)]
+pub struct GeneratorOptions {
+    pub width: usize,
+    pub height: usize,
+    /// How many XMASes to plant
+    pub words: usize,
+    /// The ways a planted XMAS may read
+    pub directions: Vec<Direction>,
+    /// How many X-MASes to plant
+    pub crosses: usize,
+    /// Whether planted answers may share letters
+    pub overlaps: bool,
+    pub seed: u64,
+}
+
+impl Default for GeneratorOptions {
+    fn default() -> Self {
+        GeneratorOptions {
+            width: 140,
+            height: 140,
+            words: 20,
+            directions: Direction::ALL.to_vec(),
+            crosses: 20,
+            overlaps: true,
+            seed: 0x2024_1204,
+        }
+    }
+}
+
+/// A puzzle along with every answer planted in it. Nothing else in the grid matches, so these are
+/// exactly what `find_xmas` and `find_mases` should find.
+#[derive(Debug, Clone)]
+pub struct GeneratedPuzzle {
+    pub puzzle: Puzzle,
+    /// Ordered the same way as `find_words`
+    pub words: Vec<WordMatch>,
+    /// Ordered the same way as `find_pattern`
+    pub crosses: Vec<PatternMatch>,
+}
+
+impl GeneratedPuzzle {
+    /// The puzzle as it would appear in an input file
+    pub fn input(&self) -> String {
+        self.puzzle
+            .rows
+            .iter()
+            .map(|row| {
+                let mut line: String = row.data.iter().collect();
+                line.push('\n');
+                line
+            })
+            .collect()
+    }
+
+    pub fn expected_xmas(&self) -> u64 {
+        self.words.len() as u64
+    }
+
+    pub fn expected_mases(&self) -> u64 {
+        self.crosses.len() as u64
+    }
+}
+
+/// Marks a cell nothing has been put in yet
+const FREE: char = '.';
+/// Used to fill a cell when every letter of XMAS would complete an answer that wasn't planted
+const SPARE: char = 'O';
+/// How many random spots to try before giving up on planting an answer
+const PLANT_ATTEMPTS: usize = 1000;
+
+/// Builds a random puzzle with the requested answers planted in it. If the grid gets too crowded
+/// to fit them all, it has fewer than asked for; the counts in the result are always exact.
+pub fn generate_puzzle(options: &GeneratorOptions) -> GeneratedPuzzle {
+    let mut rng = XorShift(options.seed.max(1));
+    let blank: String = (0..options.height)
+        .map(|_| FREE.to_string().repeat(options.width) + "\n")
+        .collect();
+    let pattern = Pattern::from_str(X_MAS).expect("Couldn't parse pattern");
+    let mut planter = Planter {
+        puzzle: Puzzle::from_str(&blank).expect("Couldn't parse from string"),
+        words: HashSet::new(),
+        crosses: HashSet::new(),
+        variants: pattern.variants(),
+        overlaps: options.overlaps,
+    };
+
+    for planted in 0..options.words {
+        if options.directions.is_empty() || !planter.plant_word(&mut rng, &options.directions) {
+            warn!("Only planted {} of {} XMASes", planted, options.words);
+            break;
+        }
+    }
+    for planted in 0..options.crosses {
+        if !planter.plant_cross(&mut rng) {
+            warn!("Only planted {} of {} X-MASes", planted, options.crosses);
+            break;
+        }
+    }
+    planter.fill(&mut rng);
+
+    let mut words: Vec<WordMatch> = planter
+        .words
+        .iter()
+        .map(|(x, y, direction)| WordMatch {
+            word: "XMAS".to_string(),
+            x: *x,
+            y: *y,
+            direction: *direction,
+        })
+        .collect();
+    words.sort_by_key(|m| (m.y, m.x, m.direction));
+    let mut crosses: Vec<PatternMatch> = planter
+        .crosses
+        .iter()
+        .map(|(x, y, transform)| PatternMatch {
+            x: *x,
+            y: *y,
+            transform: *transform,
+        })
+        .collect();
+    crosses.sort();
+
+    GeneratedPuzzle {
+        puzzle: planter.puzzle,
+        words,
+        crosses,
+    }
+}
+
+/// The half-built puzzle. The only XMASes and X-MASes in it are ever the planted ones, so putting
+/// a letter in a cell only needs checking against the answers that could run through it.
+struct Planter {
+    puzzle: Puzzle,
+    words: HashSet<(usize, usize, Direction)>,
+    crosses: HashSet<(usize, usize, Transform)>,
+    variants: Vec<(Transform, Pattern)>,
+    overlaps: bool,
+}
+
+impl Planter {
+    fn set(&mut self, x: usize, y: usize, letter: char) {
+        self.puzzle.rows[y].data[x] = letter;
+    }
+
+    /// Writes the letters into the grid, unless one clashes with what's already there. Returns
+    /// the cells that were free before.
+    fn write(&mut self, letters: &[((usize, usize), char)]) -> Option<Vec<(usize, usize)>> {
+        let mut written = Vec::new();
+        for ((x, y), letter) in letters.iter() {
+            let current = self.puzzle.rows[*y].data[*x];
+            if current == FREE {
+                written.push((*x, *y));
+            } else if current != *letter || !self.overlaps {
+                return None;
+            }
+        }
+        for ((x, y), letter) in letters.iter() {
+            self.set(*x, *y, *letter);
+        }
+        Some(written)
+    }
+
+    fn erase(&mut self, cells: &[(usize, usize)]) {
+        for (x, y) in cells.iter() {
+            self.set(*x, *y, FREE);
+        }
+    }
+
+    /// Whether an XMAS or X-MAS that wasn't planted runs through (`x`, `y`)
+    fn is_accidental(&self, x: usize, y: usize) -> bool {
+        for direction in Direction::ALL {
+            let mut start = Some((x, y));
+            for _ in 0..4 {
+                let Some((sx, sy)) = start else { break };
+                if self.is_xmas(sx, sy, direction) && !self.words.contains(&(sx, sy, direction)) {
+                    return true;
+                }
+                start = self.puzzle.step(sx, sy, direction.opposite());
+            }
+        }
+
+        for (transform, variant) in self.variants.iter() {
+            for (dy, row) in variant.cells.iter().enumerate() {
+                for (dx, cell) in row.iter().enumerate() {
+                    if cell.is_none() || dx > x || dy > y {
+                        continue;
+                    }
+                    let (ox, oy) = (x - dx, y - dy);
+                    if variant.matches_at(&self.puzzle, ox, oy)
+                        && !self.crosses.contains(&(ox, oy, *transform))
+                    {
+                        return true;
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    fn is_xmas(&self, x: usize, y: usize, direction: Direction) -> bool {
+        let mut cell = Some((x, y));
+        for letter in "XMAS".chars() {
+            match cell {
+                Some((x, y)) if self.puzzle.is_char_at(x, y, letter) => {
+                    cell = self.puzzle.step(x, y, direction)
                 }
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    /// Writes the letters and checks they didn't complete anything else, or takes them back out
+    fn try_plant(&mut self, letters: &[((usize, usize), char)]) -> bool {
+        let Some(written) = self.write(letters) else {
+            return false;
+        };
+        if written.iter().any(|(x, y)| self.is_accidental(*x, *y)) {
+            self.erase(&written);
+            return false;
+        }
+        true
+    }
+
+    fn plant_word(&mut self, rng: &mut XorShift, directions: &[Direction]) -> bool {
+        for _ in 0..PLANT_ATTEMPTS {
+            let direction = directions[rng.next(directions.len() as u64) as usize];
+            let x = rng.next(self.puzzle.width.max(1) as u64) as usize;
+            let y = rng.next(self.puzzle.height.max(1) as u64) as usize;
+            let mut letters = Vec::new();
+            let mut cell = Some((x, y));
+            for letter in "XMAS".chars() {
+                let Some((cx, cy)) = cell else { break };
+                letters.push(((cx, cy), letter));
+                cell = self.puzzle.step(cx, cy, direction);
+            }
+            if letters.len() < 4 || self.words.contains(&(x, y, direction)) {
+                continue;
+            }
+
+            self.words.insert((x, y, direction));
+            if self.try_plant(&letters) {
+                return true;
+            }
+            self.words.remove(&(x, y, direction));
+        }
+        false
+    }
+
+    fn plant_cross(&mut self, rng: &mut XorShift) -> bool {
+        for _ in 0..PLANT_ATTEMPTS {
+            let (transform, variant) =
+                &self.variants[rng.next(self.variants.len() as u64) as usize];
+            let (transform, variant) = (*transform, variant.clone());
+            if variant.width > self.puzzle.width || variant.height > self.puzzle.height {
+                return false;
+            }
+            let x = rng.next((self.puzzle.width - variant.width + 1) as u64) as usize;
+            let y = rng.next((self.puzzle.height - variant.height + 1) as u64) as usize;
+            let letters: Vec<((usize, usize), char)> = variant
+                .cells
+                .iter()
+                .enumerate()
+                .flat_map(|(dy, row)| {
+                    row.iter()
+                        .enumerate()
+                        .filter_map(move |(dx, cell)| cell.map(|letter| ((x + dx, y + dy), letter)))
+                })
+                .collect();
+            if self.crosses.contains(&(x, y, transform)) {
+                continue;
+            }
+
+            self.crosses.insert((x, y, transform));
+            if self.try_plant(&letters) {
+                return true;
+            }
+            self.crosses.remove(&(x, y, transform));
+        }
+        false
+    }
+
+    /// Fills every free cell with a random letter of XMAS that doesn't complete anything
+    fn fill(&mut self, rng: &mut XorShift) {
+        let letters = ['X', 'M', 'A', 'S'];
+        for y in 0..self.puzzle.height {
+            for x in 0..self.puzzle.width {
+                if self.puzzle.rows[y].data[x] != FREE {
+                    continue;
+                }
+                let first = rng.next(letters.len() as u64) as usize;
+                let fits = (0..letters.len())
+                    .map(|i| letters[(first + i) % letters.len()])
+                    .find(|letter| {
+                        self.set(x, y, *letter);
+                        !self.is_accidental(x, y)
+                    });
+                self.set(x, y, fits.unwrap_or(SPARE));
             }
         }
     }
-    return mases;
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    #[test]
-    fn test_known_input() {
-        let raw_puzzle = "\
+    /// The example from the puzzle
+    const EXAMPLE: &str = "\
 MMMSXXMASM
 MSAMXMSMSA
 AMXSXMAAMM
@@ -230,27 +1324,464 @@ SAXAMASAAA
 MAMMMXMMMM
 MXMXAXMASX
 ";
-        let puzzle = Puzzle::from_str(raw_puzzle).expect("Couldn't parse from string");
+
+    #[test]
+    fn test_known_input() {
+        let puzzle = Puzzle::from_str(EXAMPLE).expect("Couldn't parse from string");
         println!("{:?}", puzzle);
         assert_eq!(find_xmas(puzzle), 18);
     }
 
     #[test]
     fn test_known_input_mases() {
+        let puzzle = Puzzle::from_str(EXAMPLE).expect("Couldn't parse from string");
+        println!("{:?}", puzzle);
+        assert_eq!(find_mases(puzzle), 9);
+    }
+
+    #[test]
+    fn test_find_words() {
         let raw_puzzle = "\
-MMMSXXMASM
-MSAMXMSMSA
-AMXSXMAAMM
-MSAMASMSMX
-XMASAMXAMM
-XXAMMXXAMA
-SMSMSASXSS
-SAXAMASAAA
-MAMMMXMMMM
-MXMXAXMASX
+..X...
+.SAMX.
+.A..A.
+XMAS.S
+.X....
 ";
         let puzzle = Puzzle::from_str(raw_puzzle).expect("Couldn't parse from string");
-        println!("{:?}", puzzle);
-        assert_eq!(find_mases(puzzle), 9);
+        let matches = find_words(&puzzle, &["XMAS"], &SearchOptions::default());
+        assert_eq!(
+            matches,
+            vec![
+                WordMatch {
+                    word: "XMAS".to_string(),
+                    x: 2,
+                    y: 0,
+                    direction: Direction::DownRight
+                },
+                WordMatch {
+                    word: "XMAS".to_string(),
+                    x: 4,
+                    y: 1,
+                    direction: Direction::Left
+                },
+                WordMatch {
+                    word: "XMAS".to_string(),
+                    x: 0,
+                    y: 3,
+                    direction: Direction::Right
+                },
+                WordMatch {
+                    word: "XMAS".to_string(),
+                    x: 1,
+                    y: 4,
+                    direction: Direction::Up
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_words_options() {
+        let puzzle = Puzzle::from_str(EXAMPLE).expect("Couldn't parse from string");
+        let straight = SearchOptions {
+            diagonals: false,
+            ..Default::default()
+        };
+        let forwards = SearchOptions {
+            reversed: false,
+            ..Default::default()
+        };
+        assert_eq!(find_words(&puzzle, &["XMAS"], &straight).len(), 8);
+        assert_eq!(find_words(&puzzle, &["XMAS"], &forwards).len(), 6);
+
+        // Searching for the reversed word forwards finds the same spots as searching backwards
+        let matches = find_words(&puzzle, &["XMAS", "SAMX"], &forwards);
+        assert_eq!(matches.len(), 18);
+        assert_eq!(matches.iter().filter(|m| m.word == "SAMX").count(), 12);
+    }
+
+    fn random_puzzle(rng: &mut XorShift, width: usize, height: usize, letters: &[char]) -> Puzzle {
+        let raw_puzzle: String = (0..height)
+            .map(|_| {
+                let mut line: String = (0..width)
+                    .map(|_| letters[rng.next(letters.len() as u64) as usize])
+                    .collect();
+                line.push('\n');
+                line
+            })
+            .collect();
+        Puzzle::from_str(&raw_puzzle).expect("Couldn't parse from string")
+    }
+
+    #[test]
+    fn test_backends_agree() {
+        let mut rng = XorShift(0x2024_1204);
+        let words = ["XMAS", "MAS", "SAS", "X", "AMMA"];
+        for _ in 0..200 {
+            let width = 1 + rng.next(12) as usize;
+            let height = 1 + rng.next(12) as usize;
+            let puzzle = random_puzzle(&mut rng, width, height, &['X', 'M', 'A', 'S']);
+            for (reversed, diagonals) in
+                [(true, true), (true, false), (false, true), (false, false)]
+            {
+                let options = SearchOptions {
+                    reversed,
+                    diagonals,
+                    backend: Backend::Recursive,
+                };
+                let line_scan = SearchOptions {
+                    backend: Backend::LineScan,
+                    ..options
+                };
+                assert_eq!(
+                    find_words(&puzzle, &words, &options),
+                    find_words(&puzzle, &words, &line_scan)
+                );
+            }
+        }
+    }
+
+    /// Run with `cargo test --release bench_ -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_line_scan_large_grid() {
+        let generated = generate_puzzle(&GeneratorOptions {
+            width: 5000,
+            height: 5000,
+            words: 100_000,
+            crosses: 100_000,
+            seed: 0x5000_5000,
+            ..Default::default()
+        });
+        for backend in [Backend::LineScan, Backend::Recursive] {
+            let options = SearchOptions {
+                backend,
+                ..Default::default()
+            };
+            let start = std::time::Instant::now();
+            let found = find_words(&generated.puzzle, &["XMAS"], &options).len();
+            println!("{:?}: {} matches in {:?}", backend, found, start.elapsed());
+            assert_eq!(found as u64, generated.expected_xmas());
+        }
+    }
+
+    #[test]
+    fn test_pattern_variants() {
+        let x_mas = Pattern::from_str(X_MAS).unwrap();
+        assert_eq!(x_mas.variants().len(), 4);
+
+        let plus_mas = Pattern::from_str(".M.\n.A.\n.S.\n").unwrap();
+        assert_eq!(plus_mas.variants().len(), 4);
+        assert_eq!(
+            plus_mas.rotate(),
+            Pattern::from_str("...\nSAM\n...\n").unwrap()
+        );
+
+        let corner = Pattern::from_str("AB\nC.\n").unwrap();
+        assert_eq!(corner.variants().len(), 8);
+        assert!(Pattern::from_str("AB\nC\n").is_err());
+    }
+
+    #[test]
+    fn test_find_pattern() {
+        let raw_puzzle = "\
+.M.S
+..A.
+.M.S
+MAS.
+....
+";
+        let puzzle = Puzzle::from_str(raw_puzzle).expect("Couldn't parse from string");
+        let x_mas = Pattern::from_str(X_MAS).unwrap();
+        assert_eq!(
+            find_pattern(&puzzle, &x_mas, true),
+            vec![PatternMatch {
+                x: 1,
+                y: 0,
+                transform: Transform {
+                    rotation: 0,
+                    reflected: false
+                }
+            }]
+        );
+
+        // A plus-shaped MAS, reading across or down in either direction
+        let plus_mas = Pattern::from_str(".M.\n.A.\n.S.\n").unwrap();
+        let matches = find_pattern(&puzzle, &plus_mas, true);
+        assert_eq!(matches.len(), 1);
+        assert_eq!((matches[0].x, matches[0].y), (0, 2));
+        assert!(find_pattern(&puzzle, &plus_mas, false).is_empty());
+    }
+
+    #[test]
+    fn test_render_matches() {
+        let puzzle = Puzzle::from_str(EXAMPLE).expect("Couldn't parse from string");
+        let matches = find_words(&puzzle, &["XMAS"], &SearchOptions::default());
+        assert_eq!(
+            render_matches(&puzzle, &matches, RenderStyle::Plain, false),
+            "\
+....XXMAS.
+.SAMXMS...
+...S..A...
+..A.A.MS.X
+XMASAMX.MM
+X.....XA.A
+S.S.S.S.SS
+.A.A.A.A.A
+..M.M.M.MM
+.X.X.XMASX
+"
+        );
+
+        let ansi = render_matches(&puzzle, &matches, RenderStyle::Ansi, true);
+        // The X in the bottom right corner starts two matches
+        assert!(ansi.lines().nth(9).unwrap().ends_with("\x1b[1;33mX\x1b[0m"));
+        let html = render_matches(&puzzle, &matches, RenderStyle::Html, false);
+        assert!(html.contains("<span class=\"hit\">X</span>"));
+        assert!(!html.contains("overlap\">"));
+    }
+
+    #[test]
+    fn test_unicode_puzzle() {
+        let raw_puzzle = "\
+ΧΡΙΣΤ
+ΟΙΑΝΑ
+ΣΤΡΙΧ
+";
+        let puzzle = Puzzle::from_str(raw_puzzle).expect("Couldn't parse from string");
+        assert_eq!((puzzle.width, puzzle.height), (5, 3));
+
+        for backend in [Backend::Recursive, Backend::LineScan] {
+            let options = SearchOptions {
+                backend,
+                ..Default::default()
+            };
+            let matches = find_words(&puzzle, &["ΧΡΙΣΤ", "ΤΑΧ", "ΑΙ"], &options);
+            let found: Vec<(&str, usize, usize, Direction)> = matches
+                .iter()
+                .map(|m| (m.word.as_str(), m.x, m.y, m.direction))
+                .collect();
+            assert_eq!(
+                found,
+                vec![
+                    ("ΧΡΙΣΤ", 0, 0, Direction::Right),
+                    ("ΤΑΧ", 4, 0, Direction::Down),
+                    ("ΑΙ", 2, 1, Direction::Up),
+                    ("ΑΙ", 2, 1, Direction::Left),
+                    ("ΑΙ", 2, 1, Direction::DownRight),
+                    ("ΑΙ", 4, 1, Direction::DownLeft),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn test_ragged_rows() {
+        assert_eq!(
+            Puzzle::from_str("XMAS\nXMA\nXMAS\n").unwrap_err(),
+            RaggedRowError {
+                line: 2,
+                expected: 4,
+                found: 3
+            }
+        );
+
+        let puzzle = Puzzle::parse("XMAS\nXMA\nXMAS\n", RaggedRows::Pad('.')).unwrap();
+        assert_eq!(puzzle.width, 4);
+        assert!(puzzle.is_char_at(3, 1, '.'));
+    }
+
+    #[test]
+    fn test_torus_search() {
+        let raw_puzzle = "\
+...A
+...S
+MASX
+...M
+";
+        let puzzle = Puzzle::from_str(raw_puzzle).expect("Couldn't parse from string");
+        assert!(find_words(&puzzle, &["XMAS"], &SearchOptions::default()).is_empty());
+
+        for backend in [Backend::Recursive, Backend::LineScan] {
+            let options = SearchOptions {
+                backend,
+                ..Default::default()
+            };
+            let wrapped = puzzle.clone().with_topology(Topology::HorizontalWrap);
+            let found: Vec<(usize, usize, Direction)> = find_words(&wrapped, &["XMAS"], &options)
+                .iter()
+                .map(|m| (m.x, m.y, m.direction))
+                .collect();
+            assert_eq!(found, vec![(3, 2, Direction::Right)]);
+
+            let torus = puzzle.clone().with_topology(Topology::Torus);
+            let found: Vec<(usize, usize, Direction)> = find_words(&torus, &["XMAS"], &options)
+                .iter()
+                .map(|m| (m.x, m.y, m.direction))
+                .collect();
+            assert_eq!(
+                found,
+                vec![(3, 2, Direction::Right), (3, 2, Direction::Down)]
+            );
+            assert_eq!(
+                find_words(&torus, &["XMAS"], &options)[1].cells(&torus),
+                vec![(3, 2), (3, 3), (3, 0), (3, 1)]
+            );
+        }
+    }
+
+    #[test]
+    fn test_torus_pattern() {
+        // The X-MAS is split across every edge of the grid
+        let raw_puzzle = "\
+A..
+.SM
+.SM
+";
+        let puzzle = Puzzle::from_str(raw_puzzle).expect("Couldn't parse from string");
+        assert_eq!(find_mases(puzzle.clone()), 0);
+        assert_eq!(
+            find_mases(puzzle.clone().with_topology(Topology::HorizontalWrap)),
+            0
+        );
+
+        let torus = puzzle.with_topology(Topology::Torus);
+        let x_mas = Pattern::from_str(X_MAS).unwrap();
+        let matches = find_pattern(&torus, &x_mas, true);
+        assert_eq!(matches.len(), 1);
+        assert_eq!((matches[0].x, matches[0].y), (2, 2));
+    }
+
+    #[test]
+    fn test_masked_search() {
+        let raw_puzzle = "\
+XMAS
+XMAS
+";
+        let puzzle = Puzzle::from_str(raw_puzzle)
+            .expect("Couldn't parse from string")
+            .with_mask("..#.\n....\n");
+        for backend in [Backend::Recursive, Backend::LineScan] {
+            let options = SearchOptions {
+                backend,
+                ..Default::default()
+            };
+            let found: Vec<(usize, usize)> = find_words(&puzzle, &["XMAS", "MA"], &options)
+                .iter()
+                .map(|m| (m.x, m.y))
+                .collect();
+            // MA can still go diagonally around the blocked A
+            assert_eq!(found, vec![(0, 1), (1, 0), (1, 1)]);
+        }
+    }
+
+    #[test]
+    fn test_backends_agree_on_wrapped_and_masked_grids() {
+        let mut rng = XorShift(0x2024_0040);
+        let words = ["XMAS", "MAS", "SAMXMASX", "A"];
+        for _ in 0..200 {
+            let width = 1 + rng.next(7) as usize;
+            let height = 1 + rng.next(7) as usize;
+            let mut puzzle = random_puzzle(&mut rng, width, height, &['X', 'M', 'A', 'S']);
+            for _ in 0..rng.next(4) {
+                puzzle.block(
+                    rng.next(width as u64) as usize,
+                    rng.next(height as u64) as usize,
+                );
+            }
+            for topology in [Topology::Bounded, Topology::HorizontalWrap, Topology::Torus] {
+                let puzzle = puzzle.clone().with_topology(topology);
+                for (reversed, diagonals) in [(true, true), (false, true), (true, false)] {
+                    let options = SearchOptions {
+                        reversed,
+                        diagonals,
+                        backend: Backend::Recursive,
+                    };
+                    let line_scan = SearchOptions {
+                        backend: Backend::LineScan,
+                        ..options
+                    };
+                    assert_eq!(
+                        find_words(&puzzle, &words, &options),
+                        find_words(&puzzle, &words, &line_scan),
+                        "{:?} {:?}",
+                        topology,
+                        puzzle
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_generated_puzzles() {
+        let mut rng = XorShift(0x2024_0041);
+        for _ in 0..100 {
+            let options = GeneratorOptions {
+                width: 1 + rng.next(20) as usize,
+                height: 1 + rng.next(20) as usize,
+                words: rng.next(15) as usize,
+                crosses: rng.next(8) as usize,
+                overlaps: rng.next(2) == 0,
+                seed: 1 + rng.next(u64::MAX - 1),
+                ..Default::default()
+            };
+            let generated = generate_puzzle(&options);
+            let puzzle = Puzzle::from_str(&generated.input()).expect("Couldn't parse from string");
+            assert!(puzzle
+                .rows
+                .iter()
+                .flat_map(|row| row.data.iter())
+                .all(|c| c.is_ascii_uppercase()));
+
+            for backend in [Backend::Recursive, Backend::LineScan] {
+                let search = SearchOptions {
+                    backend,
+                    ..Default::default()
+                };
+                assert_eq!(
+                    find_words(&puzzle, &["XMAS"], &search),
+                    generated.words,
+                    "{}",
+                    generated.input()
+                );
+            }
+            let x_mas = Pattern::from_str(X_MAS).unwrap();
+            assert_eq!(find_pattern(&puzzle, &x_mas, true), generated.crosses);
+            assert_eq!(find_xmas(puzzle.clone()), generated.expected_xmas());
+            assert_eq!(find_mases(puzzle), generated.expected_mases());
+
+            if !options.overlaps {
+                let mut cells: Vec<(usize, usize)> = generated
+                    .words
+                    .iter()
+                    .flat_map(|m| m.cells(&generated.puzzle))
+                    .collect();
+                let count = cells.len();
+                cells.sort();
+                cells.dedup();
+                assert_eq!(cells.len(), count);
+            }
+        }
+    }
+
+    #[test]
+    fn test_generated_directions() {
+        let generated = generate_puzzle(&GeneratorOptions {
+            width: 30,
+            height: 30,
+            words: 40,
+            directions: vec![Direction::Right, Direction::Down],
+            crosses: 0,
+            ..Default::default()
+        });
+        assert_eq!(generated.expected_xmas(), 40);
+        assert_eq!(generated.expected_mases(), 0);
+        assert!(generated
+            .words
+            .iter()
+            .all(|m| [Direction::Right, Direction::Down].contains(&m.direction)));
+        assert_eq!(find_xmas(generated.puzzle.clone()), 40);
     }
 }
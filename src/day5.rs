@@ -1,12 +1,29 @@
-use std::{collections::HashMap, fs::read_to_string};
+use std::{
+    cmp::Ordering,
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    fmt,
+    fs::{read_to_string, write},
+    io::stdin,
+};
+
+use petgraph::{
+    algo::{tarjan_scc, toposort},
+    dot::{Config, Dot},
+    graphmap::DiGraphMap,
+    visit::{Dfs, Reversed, Walker},
+};
+
+use crate::args::{flag_value, has_flag};
 
 type Rules = HashMap<u64, Vec<u64>>;
 type Order = Vec<u64>;
 
+#[cfg(test)]
 trait Findable {
     fn find(&self, value: u64) -> Option<usize>;
 }
 
+#[cfg(test)]
 impl Findable for Order {
     fn find(&self, value: u64) -> Option<usize> {
         for (i, v) in self.into_iter().enumerate() {
@@ -18,6 +35,7 @@ impl Findable for Order {
     }
 }
 
+#[cfg(test)]
 fn is_order_valid(rules: &Rules, order: &Order) -> bool {
     let mut already_checked: Order = Vec::new();
 
@@ -35,68 +53,1102 @@ fn is_order_valid(rules: &Rules, order: &Order) -> bool {
     return true;
 }
 
+/// The rules compiled for quick checking, with duplicates removed
+#[derive(Debug, Clone, Default)]
+pub struct RuleIndex {
+    /// The pages that must come after each page, sorted
+    afters: HashMap<u64, Vec<u64>>,
+}
+
+impl RuleIndex {
+    pub fn new(rules: &Rules) -> Self {
+        let afters = rules
+            .iter()
+            .map(|(page, afters)| {
+                let mut afters = afters.clone();
+                afters.sort();
+                afters.dedup();
+                (*page, afters)
+            })
+            .collect();
+        RuleIndex { afters }
+    }
+
+    /// Whether a rule says `before` goes ahead of `after`
+    pub fn must_precede(&self, before: u64, after: u64) -> bool {
+        self.afters
+            .get(&before)
+            .is_some_and(|afters| afters.binary_search(&after).is_ok())
+    }
+
+    /// Adds the rule `before|after`, returning false if it was already there
+    pub fn insert(&mut self, before: u64, after: u64) -> bool {
+        let afters = self.afters.entry(before).or_default();
+        match afters.binary_search(&after) {
+            Ok(_) => false,
+            Err(i) => {
+                afters.insert(i, after);
+                true
+            }
+        }
+    }
+
+    /// Removes the rule `before|after`, returning false if there wasn't one
+    pub fn remove(&mut self, before: u64, after: u64) -> bool {
+        let Some(afters) = self.afters.get_mut(&before) else {
+            return false;
+        };
+        match afters.binary_search(&after) {
+            Ok(i) => {
+                afters.remove(i);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    pub fn rule_count(&self) -> usize {
+        self.afters.values().map(Vec::len).sum()
+    }
+
+    /// Looks up where each page is once, so checking costs one lookup per rule of each page
+    pub fn is_valid(&self, order: &Order) -> bool {
+        let positions: HashMap<u64, usize> =
+            order.iter().enumerate().map(|(i, p)| (*p, i)).collect();
+        order.iter().enumerate().all(|(i, page)| {
+            self.afters
+                .get(page)
+                .into_iter()
+                .flatten()
+                .all(|after| positions.get(after).is_none_or(|j| *j >= i))
+        })
+    }
+}
+
+/// Keeps track of which updates are valid while rules are added and removed. Each update
+/// remembers how many rules it breaks, so a change only has to look at the updates holding both
+/// of its pages.
+#[derive(Debug, Clone)]
+pub struct RuleEngine {
+    index: RuleIndex,
+    orders: Vec<Order>,
+    /// Where each page is in each update
+    positions: Vec<HashMap<u64, usize>>,
+    /// The updates each page appears in
+    containing: HashMap<u64, Vec<usize>>,
+    /// How many rules each update breaks
+    broken: Vec<usize>,
+}
+
+impl RuleEngine {
+    pub fn new(rules: &Rules, orders: Vec<Order>) -> Self {
+        let index = RuleIndex::new(rules);
+        let positions: Vec<HashMap<u64, usize>> = orders
+            .iter()
+            .map(|order| order.iter().enumerate().map(|(i, p)| (*p, i)).collect())
+            .collect();
+        let mut containing: HashMap<u64, Vec<usize>> = HashMap::new();
+        for (u, order) in orders.iter().enumerate() {
+            for page in order.iter() {
+                containing.entry(*page).or_default().push(u);
+            }
+        }
+        let broken = orders
+            .iter()
+            .zip(positions.iter())
+            .map(|(order, positions)| {
+                order
+                    .iter()
+                    .enumerate()
+                    .map(|(i, page)| {
+                        index
+                            .afters
+                            .get(page)
+                            .into_iter()
+                            .flatten()
+                            .filter(|after| positions.get(*after).is_some_and(|j| *j < i))
+                            .count()
+                    })
+                    .sum()
+            })
+            .collect();
+
+        RuleEngine {
+            index,
+            orders,
+            positions,
+            containing,
+            broken,
+        }
+    }
+
+    pub fn orders(&self) -> &[Order] {
+        &self.orders
+    }
+
+    pub fn is_valid(&self, update: usize) -> bool {
+        self.broken[update] == 0
+    }
+
+    /// The updates that break the rule `before|after`
+    fn breaking(&self, before: u64, after: u64) -> Vec<usize> {
+        let (Some(with_before), Some(with_after)) =
+            (self.containing.get(&before), self.containing.get(&after))
+        else {
+            return Vec::new();
+        };
+        // Only the shorter list needs walking
+        let candidates = if with_before.len() <= with_after.len() {
+            with_before
+        } else {
+            with_after
+        };
+        candidates
+            .iter()
+            .copied()
+            .filter(|u| {
+                let positions = &self.positions[*u];
+                match (positions.get(&before), positions.get(&after)) {
+                    (Some(b), Some(a)) => a < b,
+                    _ => false,
+                }
+            })
+            .collect()
+    }
+
+    /// Adds the rule `before|after`, returning the updates it made invalid
+    pub fn add_rule(&mut self, before: u64, after: u64) -> Vec<usize> {
+        if !self.index.insert(before, after) {
+            return Vec::new();
+        }
+        let mut changed = Vec::new();
+        for u in self.breaking(before, after) {
+            if self.broken[u] == 0 {
+                changed.push(u);
+            }
+            self.broken[u] += 1;
+        }
+        changed
+    }
+
+    /// Removes the rule `before|after`, returning the updates that are valid without it
+    pub fn remove_rule(&mut self, before: u64, after: u64) -> Vec<usize> {
+        if !self.index.remove(before, after) {
+            return Vec::new();
+        }
+        let mut changed = Vec::new();
+        for u in self.breaking(before, after) {
+            self.broken[u] -= 1;
+            if self.broken[u] == 0 {
+                changed.push(u);
+            }
+        }
+        changed
+    }
+
+    /// The part 1 answer for the current rules
+    pub fn valid_middle_total(&self) -> u64 {
+        self.orders
+            .iter()
+            .enumerate()
+            .filter(|(u, _)| self.is_valid(*u))
+            .map(|(_, order)| get_middle_page(order))
+            .sum()
+    }
+}
+
+/// A `--what-if` line: `+47|53` adds a rule, `-47|53` removes one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RuleChange {
+    Add(u64, u64),
+    Remove(u64, u64),
+}
+
+fn parse_rule_change(line: &str) -> Option<RuleChange> {
+    let parse_rule = |rule: &str| -> Option<(u64, u64)> {
+        let (before, after) = rule.split_once('|')?;
+        Some((before.trim().parse().ok()?, after.trim().parse().ok()?))
+    };
+    if let Some(rule) = line.strip_prefix('+') {
+        parse_rule(rule).map(|(before, after)| RuleChange::Add(before, after))
+    } else if let Some(rule) = line.strip_prefix('-') {
+        parse_rule(rule).map(|(before, after)| RuleChange::Remove(before, after))
+    } else {
+        None
+    }
+}
+
+/// Reads `+a|b` to add a rule and `-a|b` to remove one from stdin, and says what changed
+fn what_if(rules: &Rules, orders: Vec<Order>) {
+    let mut engine = RuleEngine::new(rules, orders);
+
+    for line in stdin().lines() {
+        let line = line.expect("Couldn't read from stdin");
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let changed = match parse_rule_change(line) {
+            Some(RuleChange::Add(before, after)) => engine.add_rule(before, after),
+            Some(RuleChange::Remove(before, after)) => engine.remove_rule(before, after),
+            None => {
+                eprintln!("Expected +a|b or -a|b, got {}", line);
+                continue;
+            }
+        };
+        let changed: Vec<String> = changed.iter().map(|u| (u + 1).to_string()).collect();
+        let valid = (0..engine.orders().len())
+            .filter(|u| engine.is_valid(*u))
+            .count();
+        println!(
+            "{} valid updates, middle pages add up to {}, changed: [{}]",
+            valid,
+            engine.valid_middle_total(),
+            changed.join(", ")
+        );
+    }
+}
+
+/// Compares pages by the rule between them. Pages without a rule either way compare equal, so
+/// this is only a proper ordering when the rules cover every pair of pages being sorted, which
+/// `check_consistency` can confirm.
+#[derive(Debug, Clone, Copy)]
+pub struct RuleOrdering<'a> {
+    index: &'a RuleIndex,
+}
+
+/// Three pages the rules don't order consistently: `first` relates to `second` and `second` to
+/// `third` by `relation`, but `first` doesn't relate to `third` the same way
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Intransitive {
+    pub first: u64,
+    pub second: u64,
+    pub third: u64,
+    pub relation: Ordering,
+}
+
+impl fmt::Display for Intransitive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self.relation {
+            Ordering::Less => "<",
+            Ordering::Equal => "~",
+            Ordering::Greater => ">",
+        };
+        write!(
+            f,
+            "{first} {symbol} {second} and {second} {symbol} {third}, but not {first} {symbol} {third}",
+            first = self.first,
+            second = self.second,
+            third = self.third,
+            symbol = symbol
+        )
+    }
+}
+
+/// Why the rules can't be used as a comparator for some pages
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Inconsistency {
+    /// Rules say `first` must come before `second` and `second` before `first`
+    Contradictory {
+        first: u64,
+        second: u64,
+    },
+    Intransitive(Intransitive),
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inconsistency::Contradictory { first, second } => {
+                write!(f, "{} < {} and {} < {}", first, second, second, first)
+            }
+            Inconsistency::Intransitive(intransitive) => intransitive.fmt(f),
+        }
+    }
+}
+
+impl<'a> RuleOrdering<'a> {
+    pub fn new(index: &'a RuleIndex) -> Self {
+        RuleOrdering { index }
+    }
+
+    pub fn compare(&self, a: &u64, b: &u64) -> Ordering {
+        if a == b {
+            Ordering::Equal
+        } else if self.index.must_precede(*a, *b) {
+            Ordering::Less
+        } else if self.index.must_precede(*b, *a) {
+            Ordering::Greater
+        } else {
+            Ordering::Equal
+        }
+    }
+
+    pub fn sort(&self, order: &mut Order) {
+        order.sort_by(|a, b| self.compare(a, b));
+    }
+
+    /// Sorts a copy of the update, once it's sure the comparison makes sense for its pages
+    pub fn sorted(&self, order: &Order) -> Result<Order, Inconsistency> {
+        self.check_consistency(order)?;
+        let mut sorted = order.clone();
+        self.sort(&mut sorted);
+        Ok(sorted)
+    }
+
+    /// Only neighbouring pages are compared, so this agrees with `RuleIndex::is_valid` when the
+    /// ordering is consistent for the update's pages
+    pub fn is_sorted(&self, order: &Order) -> bool {
+        order.is_sorted_by(|a, b| self.compare(a, b) != Ordering::Greater)
+    }
+
+    /// Checks every pair of `pages` for rules going both ways, then every triple for the
+    /// transitivity `sort_by` relies on, both for pages that come before one another and for
+    /// pages without a rule between them
+    pub fn check_consistency(&self, pages: &[u64]) -> Result<(), Inconsistency> {
+        for first in pages.iter() {
+            for second in pages.iter().filter(|page| *page != first) {
+                if self.index.must_precede(*first, *second)
+                    && self.index.must_precede(*second, *first)
+                {
+                    return Err(Inconsistency::Contradictory {
+                        first: *first,
+                        second: *second,
+                    });
+                }
+            }
+        }
+        for first in pages.iter() {
+            for second in pages.iter().filter(|page| *page != first) {
+                let relation = self.compare(first, second);
+                if relation == Ordering::Greater {
+                    continue;
+                }
+                for third in pages
+                    .iter()
+                    .filter(|page| *page != first && *page != second)
+                {
+                    if self.compare(second, third) == relation
+                        && self.compare(first, third) != relation
+                    {
+                        return Err(Inconsistency::Intransitive(Intransitive {
+                            first: *first,
+                            second: *second,
+                            third: *third,
+                            relation,
+                        }));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
 fn get_middle_page(order: &Order) -> u64 {
     return *order.get(order.len() / 2).expect("couldn't get middle");
 }
 
-pub fn process_input(path: &str) {
+/// Flags: `--analyze` checks the rules for cycles and says which updates have only one valid
+/// order, instead of solving the puzzle. `--explain` lists the broken rules of each invalid update
+/// and the moves that fix it. `--sort` fixes updates by sorting them with the rules as a comparator
+/// instead of a topological sort. `--export dot|json` writes the rule graph to `--output`
+/// (`day5.dot` or `day5.json` by default), or with `--update N` just the rules between the pages
+/// of the Nth update. `--what-if` reads rule changes like `+47|53` or `-47|53` from stdin and
+/// reports which updates they make valid or invalid.
+pub fn process_input(path: &str, args: &[String]) {
     // Read in the file, get the two vecs:
     let raw_input = read_to_string(path).expect("Couldn't open day5.txt");
     let (rules, orders) = raw_input_to_rules_and_orders(&raw_input);
-    // let result = orders
-    //     .into_iter()
-    //     .filter(|order| is_order_valid(&rules, order))
-    //     .map(|order| get_middle_page(&order))
-    //     .reduce(|acc, page| {
-    //         return acc + page;
-    //     })
-    //     .expect("Didn't reduce correctly");
-
-    // println!("Day 5 result: {}", result);
-
-    let invalid_result = orders
+    let index = RuleIndex::new(&rules);
+    if let Some(format) = flag_value(args, "--export") {
+        let update = flag_value(args, "--update").map(|n| {
+            let n: usize = n.parse().expect("Couldn't parse the update number");
+            orders.get(n.wrapping_sub(1)).expect("No such update")
+        });
+        let (contents, default_output) = match format {
+            "json" => (rules_to_json(&rules, update), "day5.json"),
+            _ => (rules_to_dot(&rules, update), "day5.dot"),
+        };
+        let output = flag_value(args, "--output").unwrap_or(default_output);
+        write(output, contents).expect("Couldn't write the export");
+        println!("Wrote {}", output);
+        return;
+    }
+    if has_flag(args, "--what-if") {
+        what_if(&rules, orders);
+        return;
+    }
+    if has_flag(args, "--analyze") {
+        analyze(&rules, &orders);
+        return;
+    }
+    if has_flag(args, "--explain") {
+        for order in orders.iter().filter(|order| !index.is_valid(order)) {
+            let line: Vec<String> = order.iter().map(u64::to_string).collect();
+            match explain(&rules, order) {
+                Ok(explanation) => println!("{}: {}", line.join(","), explanation),
+                Err(err) => println!("{}: no valid order, {}", line.join(","), err),
+            }
+        }
+    }
+    let sort = has_flag(args, "--sort");
+    let ordering = RuleOrdering::new(&index);
+    let invalid_result: u64 = orders
         .into_iter()
-        .filter(|order| !is_order_valid(&rules, order))
-        .map(|order| correct_invalid_order(&rules, &order))
-        .map(|order| get_middle_page(&order))
-        .reduce(|acc, page| {
-            return acc + page;
+        .filter(|order| {
+            if sort {
+                // Inconsistent updates are kept so `sorted` can say what's wrong with them
+                ordering.check_consistency(order).is_err() || !ordering.is_sorted(order)
+            } else {
+                !index.is_valid(order)
+            }
         })
-        .expect("Didn't reduce correctly");
+        .filter_map(|order| {
+            let fixed = if sort {
+                ordering.sorted(&order).map_err(|err| err.to_string())
+            } else {
+                reorder(&rules, &order).map_err(|err| err.to_string())
+            };
+            match fixed {
+                Ok(fixed) => {
+                    let middles = possible_middle_pages(&rules, &order);
+                    if middles.len() > 1 {
+                        log::warn!(
+                            "The rules allow {:?} to be the middle page of {:?}",
+                            middles,
+                            order
+                        );
+                    }
+                    Some(get_middle_page(&fixed))
+                }
+                Err(err) => {
+                    log::error!("Couldn't reorder {:?}: {}", order, err);
+                    None
+                }
+            }
+        })
+        .sum();
     println!("Day 5 part 2 result: {}", invalid_result);
 }
 
 fn raw_input_to_rules_and_orders(raw_input: &str) -> (Rules, Vec<Order>) {
+    parse_input(raw_input).unwrap_or_else(|err| panic!("Couldn't parse the input: {}", err))
+}
+
+/// A line of the input that isn't a rule or an update. Lines are numbered from 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Reads the rules, then the updates. Either line ending works, blank lines and lines starting
+/// with `#` are skipped, and either section can be empty. Rules can't come after the first update.
+fn parse_input(raw_input: &str) -> Result<(Rules, Vec<Order>), ParseError> {
     let mut rules: Rules = HashMap::new();
     let mut orders: Vec<Order> = Vec::new();
-    let (raw_rules, raw_orders) = raw_input
-        .split_once("\n\n")
-        .expect("Couldn't find the space between");
-
-    for line in raw_rules.lines().into_iter() {
-        let (left, right) = line.split_once('|').expect("Couldn't split on |");
-        let left = left.parse::<u64>().expect("Couldnt' parse as number");
-        let right = right.parse::<u64>().expect("Couldnt' parse as number");
-
-        rules
-            .entry(left)
-            .and_modify(|e| e.push(right))
-            .or_insert_with(|| {
-                return vec![right];
-            });
-    }
-
-    for line in raw_orders.lines().into_iter() {
-        orders.push(
-            line.split(',')
-                .map(|x| x.parse::<u64>().expect("couldn't parse number"))
+
+    for (i, line) in raw_input.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |message: String| ParseError {
+            line: i + 1,
+            message,
+        };
+        let page = |raw: &str| {
+            raw.trim()
+                .parse::<u64>()
+                .map_err(|_| error(format!("\"{}\" isn't a page number", raw.trim())))
+        };
+
+        if let Some((left, right)) = line.split_once('|') {
+            if !orders.is_empty() {
+                return Err(error("rules have to come before the updates".to_string()));
+            }
+            rules.entry(page(left)?).or_default().push(page(right)?);
+        } else {
+            let order = line.split(',').map(page).collect::<Result<Order, _>>()?;
+            orders.push(order);
+        }
+    }
+
+    Ok((rules, orders))
+}
+
+/// The rules that apply to an update's pages contradict each other, so there's no valid order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CycleError {
+    /// A page on the cycle
+    pub page: u64,
+}
+
+impl fmt::Display for CycleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the rules form a cycle through page {}", self.page)
+    }
+}
+
+/// The rules restricted to the pages in `order`, as edges from each page to the pages that must
+/// come after it
+fn update_graph(rules: &Rules, order: &Order) -> DiGraphMap<u64, ()> {
+    let pages: HashSet<u64> = order.iter().copied().collect();
+    let mut graph = DiGraphMap::new();
+    for page in order.iter() {
+        graph.add_node(*page);
+        for after in rules.get(page).into_iter().flatten() {
+            if pages.contains(after) {
+                graph.add_edge(*page, *after, ());
+            }
+        }
+    }
+    graph
+}
+
+/// Puts the pages of `order` in an order that satisfies every rule between them
+fn reorder(rules: &Rules, order: &Order) -> Result<Order, CycleError> {
+    toposort(&update_graph(rules, order), None).map_err(|cycle| CycleError {
+        page: cycle.node_id(),
+    })
+}
+
+/// A rule `before|after` that the update breaks by putting `after` first. Positions start at 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleViolation {
+    pub before: u64,
+    pub after: u64,
+    pub before_position: usize,
+    pub after_position: usize,
+}
+
+impl fmt::Display for RuleViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}|{} ({} at {}, {} at {})",
+            self.before,
+            self.after,
+            self.before,
+            self.before_position,
+            self.after,
+            self.after_position
+        )
+    }
+}
+
+/// Takes `page` out of the update and puts it back at position `to` of the fixed order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Move {
+    pub page: u64,
+    pub from: usize,
+    pub to: usize,
+}
+
+/// Why an update is invalid and how to fix it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Explanation {
+    pub violations: Vec<RuleViolation>,
+    /// Applying these in order, each page removed first, gives `fixed`
+    pub moves: Vec<Move>,
+    pub fixed: Order,
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.violations.is_empty() {
+            return write!(f, "valid");
+        }
+        let violations: Vec<String> = self.violations.iter().map(|v| v.to_string()).collect();
+        let moves: Vec<String> = self
+            .moves
+            .iter()
+            .map(|m| format!("move {} from {} to {}", m.page, m.from, m.to))
+            .collect();
+        write!(f, "broke {}; {}", violations.join(", "), moves.join(", "))
+    }
+}
+
+/// Every rule the update breaks, ordered by where the page that should come first is
+fn violations(rules: &Rules, order: &Order) -> Vec<RuleViolation> {
+    let positions: HashMap<u64, usize> = order.iter().enumerate().map(|(i, p)| (*p, i)).collect();
+    let mut violations = Vec::new();
+    for (before_position, before) in order.iter().enumerate() {
+        for after in rules.get(before).into_iter().flatten() {
+            if let Some(after_position) = positions.get(after) {
+                if *after_position < before_position {
+                    violations.push(RuleViolation {
+                        before: *before,
+                        after: *after,
+                        before_position,
+                        after_position: *after_position,
+                    });
+                }
+            }
+        }
+    }
+    violations.sort_by_key(|v| (v.before_position, v.after_position));
+    violations
+}
+
+/// Lists what's wrong with the update and the fewest pages to move to fix it. The pages left
+/// alone are the most that can stay in their current order, and the fixed update is a valid
+/// order that keeps them that way.
+fn explain(rules: &Rules, order: &Order) -> Result<Explanation, CycleError> {
+    // Fails if there's no valid order at all
+    reorder(rules, order)?;
+    let mut graph = update_graph(rules, order);
+    let kept = pages_to_keep(&graph, order);
+
+    // Pin the kept pages in their current order, then fit everything else around them
+    let mut kept_in_order = order.iter().enumerate().filter(|(i, _)| kept.contains(i));
+    if let Some((_, mut previous)) = kept_in_order.next() {
+        for (_, page) in kept_in_order {
+            graph.add_edge(*previous, *page, ());
+            previous = page;
+        }
+    }
+    let fixed = toposort(&graph, None).expect("Kept pages can't contradict the rules");
+    let target: HashMap<u64, usize> = fixed.iter().enumerate().map(|(i, p)| (*p, i)).collect();
+
+    let mut moves: Vec<Move> = order
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !kept.contains(i))
+        .map(|(from, page)| Move {
+            page: *page,
+            from,
+            to: target[page],
+        })
+        .collect();
+    // Putting pages back from the front means the ones already in place don't shift afterwards
+    moves.sort_by_key(|m| m.to);
+
+    Ok(Explanation {
+        violations: violations(rules, order),
+        moves,
+        fixed,
+    })
+}
+
+/// The positions of the largest set of pages with no rule between any two of them broken,
+/// following rules through other pages too. Such a set can always stay where it is.
+///
+/// Calling it an inversion when a later page has to come before an earlier one, inversions form
+/// a partial order, and the pages to keep are its largest antichain. That's found from a maximum
+/// matching between earlier and later pages of each inversion, as in Dilworth's theorem.
+fn pages_to_keep(graph: &DiGraphMap<u64, ()>, order: &Order) -> HashSet<usize> {
+    let n = order.len();
+    let position: HashMap<u64, usize> = order.iter().enumerate().map(|(i, p)| (*p, i)).collect();
+    // inversions[i] holds every later position whose page has to come before the page at i
+    let mut inversions: Vec<Vec<usize>> = vec![Vec::new(); n];
+    for (j, page) in order.iter().enumerate() {
+        for i in Dfs::new(graph, *page)
+            .iter(graph)
+            .map(|after| position[&after])
+        {
+            if i < j {
+                inversions[i].push(j);
+            }
+        }
+    }
+
+    // matched[j] is the earlier position matched with later position j
+    let mut matched: Vec<Option<usize>> = vec![None; n];
+    for i in 0..n {
+        augment(i, &inversions, &mut matched, &mut vec![false; n]);
+    }
+
+    // Follow alternating paths from every earlier position left unmatched. By König's theorem,
+    // positions reached as an earlier page but not as a later one make up the antichain.
+    let mut is_matched = vec![false; n];
+    for i in matched.iter().flatten() {
+        is_matched[*i] = true;
+    }
+    let mut reached_earlier: Vec<bool> = is_matched.iter().map(|matched| !matched).collect();
+    let mut reached_later = vec![false; n];
+    let mut stack: Vec<usize> = (0..n).filter(|i| !is_matched[*i]).collect();
+    while let Some(i) = stack.pop() {
+        for j in inversions[i].iter() {
+            if reached_later[*j] || matched[*j] == Some(i) {
+                continue;
+            }
+            reached_later[*j] = true;
+            if let Some(k) = matched[*j] {
+                if !reached_earlier[k] {
+                    reached_earlier[k] = true;
+                    stack.push(k);
+                }
+            }
+        }
+    }
+    (0..n)
+        .filter(|i| reached_earlier[*i] && !reached_later[*i])
+        .collect()
+}
+
+/// Looks for an augmenting path from earlier position `i` for the matching in `pages_to_keep`
+fn augment(
+    i: usize,
+    inversions: &[Vec<usize>],
+    matched: &mut [Option<usize>],
+    seen: &mut [bool],
+) -> bool {
+    for j in inversions[i].iter() {
+        if seen[*j] {
+            continue;
+        }
+        seen[*j] = true;
+        let free = match matched[*j] {
+            None => true,
+            Some(k) => augment(k, inversions, matched, seen),
+        };
+        if free {
+            matched[*j] = Some(i);
+            return true;
+        }
+    }
+    false
+}
+
+/// Updates with more pages than this are too big to count orders for
+const MAX_COUNTED_PAGES: usize = 20;
+
+/// How many orders of the update's pages satisfy the rules, found by counting the ways to order
+/// each subset of pages that could come first. `None` if the update is too long to count.
+fn count_orders(rules: &Rules, order: &Order) -> Option<u64> {
+    if order.len() > MAX_COUNTED_PAGES {
+        return None;
+    }
+    let graph = update_graph(rules, order);
+    // The pages that have to come before each page, as bits of their positions in the update
+    let befores: Vec<usize> = order
+        .iter()
+        .map(|page| {
+            graph
+                .neighbors_directed(*page, petgraph::Direction::Incoming)
+                .map(|before| 1 << order.iter().position(|p| *p == before).unwrap())
+                .fold(0, |mask, bit| mask | bit)
+        })
+        .collect();
+
+    let mut ways = vec![0u64; 1 << order.len()];
+    ways[0] = 1;
+    for placed in 0..ways.len() {
+        if ways[placed] == 0 {
+            continue;
+        }
+        for (i, before) in befores.iter().enumerate() {
+            if placed & (1 << i) == 0 && placed & before == *before {
+                ways[placed | (1 << i)] += ways[placed];
+            }
+        }
+    }
+    ways.last().copied()
+}
+
+/// Every valid order of the update's pages, produced one at a time by backtracking
+fn orderings(rules: &Rules, order: &Order) -> Orderings {
+    let graph = update_graph(rules, order);
+    let position = |page: u64| order.iter().position(|p| *p == page).unwrap();
+    let afters: Vec<Vec<usize>> = order
+        .iter()
+        .map(|page| graph.neighbors(*page).map(position).collect())
+        .collect();
+    let mut waiting = vec![0; order.len()];
+    for after in afters.iter().flatten() {
+        waiting[*after] += 1;
+    }
+    let mut orderings = Orderings {
+        pages: order.clone(),
+        afters,
+        waiting,
+        placed: vec![false; order.len()],
+        prefix: Vec::new(),
+        stack: Vec::new(),
+        empty: order.is_empty(),
+    };
+    let first = orderings.candidates();
+    orderings.stack.push((first, 0));
+    orderings
+}
+
+/// Iterator over the valid orders of an update, see `orderings`
+pub struct Orderings {
+    pages: Order,
+    /// The positions of the pages that must come after each page
+    afters: Vec<Vec<usize>>,
+    /// How many pages each page is still waiting on
+    waiting: Vec<usize>,
+    placed: Vec<bool>,
+    prefix: Vec<usize>,
+    /// The pages that could go next at each step of `prefix`, and which one to try next
+    stack: Vec<(Vec<usize>, usize)>,
+    /// An empty update has exactly one order, which still needs handing out
+    empty: bool,
+}
+
+impl Orderings {
+    fn candidates(&self) -> Vec<usize> {
+        (0..self.pages.len())
+            .filter(|i| !self.placed[*i] && self.waiting[*i] == 0)
+            .collect()
+    }
+
+    fn place(&mut self, i: usize) {
+        self.placed[i] = true;
+        self.prefix.push(i);
+        for after in self.afters[i].iter() {
+            self.waiting[*after] -= 1;
+        }
+    }
+
+    fn unplace(&mut self) {
+        let i = self.prefix.pop().expect("Nothing to take back");
+        self.placed[i] = false;
+        for after in self.afters[i].iter() {
+            self.waiting[*after] += 1;
+        }
+    }
+}
+
+impl Iterator for Orderings {
+    type Item = Order;
+
+    fn next(&mut self) -> Option<Order> {
+        if self.empty {
+            self.empty = false;
+            self.stack.clear();
+            return Some(Vec::new());
+        }
+        loop {
+            let (candidates, next) = self.stack.last()?;
+            let (page, next) = (candidates.get(*next).copied(), *next);
+            // Take back whatever was tried last at this step before trying the next one
+            if next > 0 {
+                self.unplace();
+            }
+            let Some(page) = page else {
+                self.stack.pop();
+                continue;
+            };
+            self.stack.last_mut().unwrap().1 += 1;
+            self.place(page);
+
+            if self.prefix.len() == self.pages.len() {
+                return Some(self.prefix.iter().map(|i| self.pages[*i]).collect());
+            }
+            let candidates = self.candidates();
+            self.stack.push((candidates, 0));
+        }
+    }
+}
+
+/// Every page that's in the middle of at least one valid order of the update. A page can go
+/// anywhere after all the pages that must come before it and ahead of all those that must come
+/// after it, directly or not.
+fn possible_middle_pages(rules: &Rules, order: &Order) -> Vec<u64> {
+    let graph = update_graph(rules, order);
+    let middle = order.len() / 2;
+    order
+        .iter()
+        .filter(|page| {
+            let before = Dfs::new(Reversed(&graph), **page)
+                .iter(Reversed(&graph))
+                .count()
+                - 1;
+            let after = Dfs::new(&graph, **page).iter(&graph).count() - 1;
+            before <= middle && middle + after < order.len()
+        })
+        .copied()
+        .collect()
+}
+
+/// Every rule as an edge from a page to one that must come after it
+fn rule_graph(rules: &Rules) -> DiGraphMap<u64, ()> {
+    let mut graph = DiGraphMap::new();
+    for (page, afters) in rules.iter() {
+        for after in afters.iter() {
+            graph.add_edge(*page, *after, ());
+        }
+    }
+    graph
+}
+
+/// The graph to export: every rule, or only those between the pages of `update`. Each edge is
+/// marked with whether the update breaks it.
+fn export_graph(rules: &Rules, update: Option<&Order>) -> DiGraphMap<u64, bool> {
+    let (source, broken): (_, HashSet<(u64, u64)>) = match update {
+        None => (rule_graph(rules), HashSet::new()),
+        Some(order) => (
+            update_graph(rules, order),
+            violations(rules, order)
+                .iter()
+                .map(|v| (v.before, v.after))
                 .collect(),
-        );
+        ),
+    };
+    let mut graph = DiGraphMap::new();
+    for page in source.nodes() {
+        graph.add_node(page);
     }
+    for (before, after, _) in source.all_edges() {
+        graph.add_edge(before, after, broken.contains(&(before, after)));
+    }
+    graph
+}
+
+/// The rules as a Graphviz graph, with any the update breaks in red
+fn rules_to_dot(rules: &Rules, update: Option<&Order>) -> String {
+    let graph = export_graph(rules, update);
+    let dot = Dot::with_attr_getters(
+        &graph,
+        &[Config::EdgeNoLabel],
+        &|_, (_, _, broken)| {
+            if *broken {
+                "color=red".to_string()
+            } else {
+                String::new()
+            }
+        },
+        &|_, _| String::new(),
+    );
+    format!("{}", dot)
+}
+
+/// The rules as a JSON object from each page to the pages that must come after it
+fn rules_to_json(rules: &Rules, update: Option<&Order>) -> String {
+    let graph = export_graph(rules, update);
+    let mut pages: Vec<u64> = graph.nodes().collect();
+    pages.sort();
+    let entries: Vec<String> = pages
+        .iter()
+        .map(|page| {
+            let mut afters: Vec<u64> = graph.neighbors(*page).collect();
+            afters.sort();
+            let afters: Vec<String> = afters.iter().map(u64::to_string).collect();
+            format!("\"{}\":[{}]", page, afters.join(","))
+        })
+        .collect();
+    format!("{{{}}}", entries.join(","))
+}
 
-    return (rules, orders);
+/// The shortest cycle in each strongly connected component of the rules, starting from its
+/// smallest page. The cycle's last page must come before its first again.
+fn rule_cycles(rules: &Rules) -> Vec<Vec<u64>> {
+    let graph = rule_graph(rules);
+    let mut cycles: Vec<Vec<u64>> = tarjan_scc(&graph)
+        .iter()
+        .filter_map(|component| shortest_cycle(&graph, component))
+        .collect();
+    cycles.sort();
+    cycles
 }
 
+/// Searches breadth-first from every page in the component for the quickest way back to it
+fn shortest_cycle(graph: &DiGraphMap<u64, ()>, component: &[u64]) -> Option<Vec<u64>> {
+    let members: HashSet<u64> = component.iter().copied().collect();
+    let mut starts = component.to_vec();
+    starts.sort();
+    let mut shortest: Option<Vec<u64>> = None;
+
+    for start in starts {
+        let mut parents: HashMap<u64, u64> = HashMap::new();
+        let mut queue = VecDeque::from([start]);
+        'search: while let Some(page) = queue.pop_front() {
+            for next in graph.neighbors(page) {
+                if !members.contains(&next) {
+                    continue;
+                }
+                if next == start {
+                    let mut cycle = vec![page];
+                    while cycle[cycle.len() - 1] != start {
+                        cycle.push(parents[&cycle[cycle.len() - 1]]);
+                    }
+                    cycle.reverse();
+                    if shortest
+                        .as_ref()
+                        .is_none_or(|best| cycle.len() < best.len())
+                    {
+                        shortest = Some(cycle);
+                    }
+                    break 'search;
+                }
+                if let Entry::Vacant(entry) = parents.entry(next) {
+                    entry.insert(page);
+                    queue.push_back(next);
+                }
+            }
+        }
+    }
+    shortest
+}
+
+/// Whether the rules between the pages of `order` allow exactly one way to arrange them. That's
+/// the case when every page in the sorted order has to come right before the next one.
+fn has_unique_order(rules: &Rules, order: &Order) -> Result<bool, CycleError> {
+    let graph = update_graph(rules, order);
+    let sorted = toposort(&graph, None).map_err(|cycle| CycleError {
+        page: cycle.node_id(),
+    })?;
+    Ok(sorted
+        .windows(2)
+        .all(|pair| graph.contains_edge(pair[0], pair[1])))
+}
+
+fn analyze(rules: &Rules, orders: &[Order]) {
+    let graph = rule_graph(rules);
+    println!(
+        "{} pages, {} rules",
+        graph.node_count(),
+        RuleIndex::new(rules).rule_count()
+    );
+    let cycles = rule_cycles(rules);
+    if cycles.is_empty() {
+        println!("The rules have no cycles");
+    }
+    for cycle in cycles.iter() {
+        let pages: Vec<String> = cycle
+            .iter()
+            .chain(cycle.first())
+            .map(u64::to_string)
+            .collect();
+        println!("Cycle: {}", pages.join(" -> "));
+    }
+
+    for (i, order) in orders.iter().enumerate() {
+        let verdict = match has_unique_order(rules, order) {
+            Ok(true) => "one valid order".to_string(),
+            Ok(false) => {
+                let count = count_orders(rules, order)
+                    .map_or("several".to_string(), |count| count.to_string());
+                let first: Vec<String> = orderings(rules, order)
+                    .next()
+                    .into_iter()
+                    .flatten()
+                    .map(|page| page.to_string())
+                    .collect();
+                format!(
+                    "{} valid orders, such as {}, middle page could be {:?}",
+                    count,
+                    first.join(","),
+                    possible_middle_pages(rules, order)
+                )
+            }
+            Err(err) => format!("no valid order, {}", err),
+        };
+        println!("Update {}: {}", i + 1, verdict);
+    }
+}
+
+#[cfg(test)]
 fn correct_invalid_order(rules: &Rules, invalid_order: &Order) -> Order {
     // Like a normal check, but switch pages when we hit a rule violation
     let mut already_checked: Order = Vec::new();
@@ -129,10 +1181,10 @@ fn correct_invalid_order(rules: &Rules, invalid_order: &Order) -> Order {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::rng::XorShift;
 
-    #[test]
-    fn test_known_input() {
-        let raw_input = "\
+    /// The example from the puzzle
+    const EXAMPLE: &str = "\
 47|53
 97|13
 97|61
@@ -163,7 +1215,9 @@ mod tests {
 97,13,75,29,47
 ";
 
-        let (rules, orders) = raw_input_to_rules_and_orders(raw_input);
+    #[test]
+    fn test_known_input() {
+        let (rules, orders) = raw_input_to_rules_and_orders(EXAMPLE);
         println!("{:?}\n\n{:?}", rules, orders);
 
         let valid_orders: Vec<Vec<u64>> = orders
@@ -188,38 +1242,7 @@ mod tests {
 
     #[test]
     fn test_known_input_part_2() {
-        let raw_input = "\
-47|53
-97|13
-97|61
-97|47
-75|29
-61|13
-75|53
-29|13
-97|29
-53|29
-61|53
-97|53
-61|29
-47|13
-75|47
-97|75
-47|61
-75|61
-47|29
-75|13
-53|13
-
-75,47,61,53,29
-97,61,53,29,13
-75,29,13
-75,97,47,61,53
-61,13,29
-97,13,75,29,47
-";
-
-        let (rules, orders) = raw_input_to_rules_and_orders(raw_input);
+        let (rules, orders) = raw_input_to_rules_and_orders(EXAMPLE);
 
         let invalid_orders: Vec<Vec<u64>> = orders
             .into_iter()
@@ -241,4 +1264,514 @@ mod tests {
             .collect();
         assert_eq!(middle_pages, vec![47, 29, 47]);
     }
+
+    #[test]
+    fn test_reorder() {
+        let (rules, orders) = raw_input_to_rules_and_orders(EXAMPLE);
+        let orders: Vec<Order> = orders
+            .into_iter()
+            .filter(|order| !is_order_valid(&rules, order))
+            .collect();
+        let reordered: Vec<Order> = orders
+            .iter()
+            .map(|order| reorder(&rules, order).expect("Couldn't reorder"))
+            .collect();
+        assert_eq!(
+            reordered,
+            vec![
+                vec![97, 75, 47, 61, 53],
+                vec![61, 29, 13],
+                vec![97, 75, 47, 29, 13],
+            ]
+        );
+        for (order, fixed) in orders.iter().zip(reordered.iter()) {
+            assert!(is_order_valid(&rules, fixed));
+            assert_eq!(correct_invalid_order(&rules, order), *fixed);
+        }
+    }
+
+    #[test]
+    fn test_reorder_cycle() {
+        let (rules, orders) = raw_input_to_rules_and_orders("1|2\n2|3\n3|1\n\n3,2,1\n1,2\n");
+        let err = reorder(&rules, &orders[0]).unwrap_err();
+        assert!([1, 2, 3].contains(&err.page));
+
+        // Rules about pages that aren't in the update don't count
+        assert_eq!(reorder(&rules, &orders[1]), Ok(vec![1, 2]));
+    }
+
+    #[test]
+    fn test_rule_cycles() {
+        let (rules, orders) =
+            raw_input_to_rules_and_orders("1|2\n2|3\n3|1\n3|4\n4|3\n5|5\n6|7\n\n1,2,3\n1,2\n6,7\n");
+        assert_eq!(rule_cycles(&rules), vec![vec![3, 4], vec![5]]);
+        assert!(has_unique_order(&rules, &orders[0]).is_err());
+        assert_eq!(has_unique_order(&rules, &orders[1]), Ok(true));
+        assert_eq!(has_unique_order(&rules, &orders[2]), Ok(true));
+
+        let (rules, orders) = raw_input_to_rules_and_orders("1|2\n1|3\n\n3,2,1\n");
+        assert!(rule_cycles(&rules).is_empty());
+        assert_eq!(has_unique_order(&rules, &orders[0]), Ok(false));
+    }
+
+    /// Takes each moved page out, then puts them back where they belong
+    fn apply_moves(order: &Order, moves: &[Move]) -> Order {
+        let mut result: Order = order
+            .iter()
+            .filter(|page| !moves.iter().any(|m| m.page == **page))
+            .copied()
+            .collect();
+        for m in moves.iter() {
+            result.insert(m.to, m.page);
+        }
+        result
+    }
+
+    #[test]
+    fn test_explain() {
+        let (rules, orders) = raw_input_to_rules_and_orders(EXAMPLE);
+        let explanation = explain(&rules, &orders[3]).unwrap();
+        assert_eq!(
+            explanation.violations,
+            vec![RuleViolation {
+                before: 97,
+                after: 75,
+                before_position: 1,
+                after_position: 0,
+            }]
+        );
+        assert_eq!(
+            explanation.to_string(),
+            "broke 97|75 (97 at 1, 75 at 0); move 75 from 0 to 1"
+        );
+
+        let explanation = explain(&rules, &orders[5]).unwrap();
+        assert_eq!(explanation.violations.len(), 4);
+        assert_eq!(
+            explanation.moves,
+            vec![
+                Move {
+                    page: 29,
+                    from: 3,
+                    to: 3
+                },
+                Move {
+                    page: 13,
+                    from: 1,
+                    to: 4
+                },
+            ]
+        );
+
+        for order in orders.iter() {
+            let explanation = explain(&rules, order).unwrap();
+            assert_eq!(
+                explanation.violations.is_empty(),
+                is_order_valid(&rules, order)
+            );
+            assert_eq!(apply_moves(order, &explanation.moves), explanation.fixed);
+        }
+        assert_eq!(explain(&rules, &orders[0]).unwrap().to_string(), "valid");
+    }
+
+    #[test]
+    fn test_explain_under_constrained() {
+        // Moving either 1 or 2 is enough, whatever order sorting by the rules would pick
+        let (rules, orders) = raw_input_to_rules_and_orders("1|2\n\n2,3,4,5,1\n");
+        let explanation = explain(&rules, &orders[0]).unwrap();
+        assert_eq!(
+            explanation.moves,
+            vec![Move {
+                page: 2,
+                from: 0,
+                to: 4
+            }]
+        );
+        assert_eq!(explanation.fixed, vec![3, 4, 5, 1, 2]);
+
+        // A rule through a page that isn't moving still counts
+        let (rules, orders) = raw_input_to_rules_and_orders("1|3\n3|2\n\n2,3,1\n");
+        let explanation = explain(&rules, &orders[0]).unwrap();
+        assert_eq!(explanation.moves.len(), 2);
+        assert_eq!(explanation.fixed, vec![1, 3, 2]);
+    }
+
+    #[test]
+    fn test_explain_moves_are_minimal() {
+        let mut rng = XorShift(0x2024_0044);
+        for _ in 0..200 {
+            let pages = 1 + rng.next(8) as usize;
+            let (rules, orders) = generate_input(&mut rng, pages, pages, 3, pages);
+            for order in orders.iter() {
+                let explanation = explain(&rules, order).unwrap();
+                assert!(is_order_valid(&rules, &explanation.fixed));
+                assert_eq!(apply_moves(order, &explanation.moves), explanation.fixed);
+
+                // The most pages that could stay, by trying every subset
+                let graph = update_graph(&rules, order);
+                let must_precede =
+                    |a: u64, b: u64| Dfs::new(&graph, a).iter(&graph).any(|p| p == b);
+                let most_kept = (0u32..1 << order.len())
+                    .filter(|subset| {
+                        (0..order.len()).all(|i| {
+                            (i + 1..order.len()).all(|j| {
+                                subset & (1 << i) == 0
+                                    || subset & (1 << j) == 0
+                                    || !must_precede(order[j], order[i])
+                            })
+                        })
+                    })
+                    .map(u32::count_ones)
+                    .max()
+                    .unwrap();
+                assert_eq!(
+                    explanation.moves.len(),
+                    order.len() - most_kept as usize,
+                    "{:?} {:?}",
+                    rules,
+                    order
+                );
+            }
+        }
+    }
+
+    /// Rules that all agree with one hidden order of `pages` pages, some repeated, and updates of
+    /// `length` distinct pages in a random order
+    fn generate_input(
+        rng: &mut XorShift,
+        pages: usize,
+        rules: usize,
+        updates: usize,
+        length: usize,
+    ) -> (Rules, Vec<Order>) {
+        let mut hidden: Order = (0..pages as u64).map(|page| page * 7 + 10).collect();
+        for i in (1..hidden.len()).rev() {
+            hidden.swap(i, rng.next(i as u64 + 1) as usize);
+        }
+
+        let mut generated: Rules = HashMap::new();
+        for _ in 0..rules {
+            let a = rng.next(pages as u64) as usize;
+            let b = rng.next(pages as u64) as usize;
+            if a != b {
+                let (before, after) = (a.min(b), a.max(b));
+                generated
+                    .entry(hidden[before])
+                    .or_default()
+                    .push(hidden[after]);
+            }
+        }
+
+        let orders = (0..updates)
+            .map(|_| {
+                let mut order = hidden.clone();
+                for i in 0..length.min(pages) {
+                    let j = i + rng.next((order.len() - i) as u64) as usize;
+                    order.swap(i, j);
+                }
+                order.truncate(length);
+                // Leave some in the hidden order so not every update is invalid
+                if rng.next(2) == 0 {
+                    let position: HashMap<u64, usize> =
+                        hidden.iter().enumerate().map(|(i, p)| (*p, i)).collect();
+                    order.sort_by_key(|page| position[page]);
+                }
+                order
+            })
+            .collect();
+        (generated, orders)
+    }
+
+    #[test]
+    fn test_rule_index() {
+        let (rules, orders) =
+            raw_input_to_rules_and_orders("1|2\n1|2\n2|3\n1|2\n\n1,2,3\n3,1\n2\n");
+        let index = RuleIndex::new(&rules);
+        assert_eq!(index.rule_count(), 2);
+        let valid: Vec<bool> = orders.iter().map(|order| index.is_valid(order)).collect();
+        assert_eq!(valid, vec![true, true, true]);
+        assert!(!index.is_valid(&vec![2, 1]));
+
+        let mut rng = XorShift(0x2024_1205);
+        for _ in 0..50 {
+            let pages = 2 + rng.next(30) as usize;
+            let (rules, orders) = generate_input(&mut rng, pages, pages * 3, 20, pages / 2 + 1);
+            let index = RuleIndex::new(&rules);
+            for order in orders.iter() {
+                assert_eq!(index.is_valid(order), is_order_valid(&rules, order));
+            }
+        }
+    }
+
+    /// Run with `cargo test --release bench_ -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_rule_index() {
+        let mut rng = XorShift(0x5000_0005);
+        let (rules, orders) = generate_input(&mut rng, 5000, 200_000, 200, 2000);
+
+        let start = std::time::Instant::now();
+        let index = RuleIndex::new(&rules);
+        let fast: Vec<bool> = orders.iter().map(|order| index.is_valid(order)).collect();
+        println!(
+            "RuleIndex: {} rules, {} valid in {:?}",
+            index.rule_count(),
+            fast.iter().filter(|valid| **valid).count(),
+            start.elapsed()
+        );
+
+        let start = std::time::Instant::now();
+        let slow: Vec<bool> = orders
+            .iter()
+            .map(|order| is_order_valid(&rules, order))
+            .collect();
+        println!("is_order_valid: {:?}", start.elapsed());
+        assert_eq!(fast, slow);
+    }
+
+    #[test]
+    fn test_rule_ordering_on_real_input() {
+        let raw_input = read_to_string("./inputs/day5.txt").expect("Couldn't open day5.txt");
+        let (rules, orders) = raw_input_to_rules_and_orders(&raw_input);
+        let index = RuleIndex::new(&rules);
+        let ordering = RuleOrdering::new(&index);
+
+        for order in orders.iter() {
+            assert_eq!(ordering.check_consistency(order), Ok(()));
+            assert_eq!(ordering.is_sorted(order), is_order_valid(&rules, order));
+
+            let sorted = ordering.sorted(order).unwrap();
+            assert!(ordering.is_sorted(&sorted));
+            if !is_order_valid(&rules, order) {
+                assert_eq!(sorted, correct_invalid_order(&rules, order));
+            }
+        }
+
+        // Taken as a whole, the rules go round in a cycle
+        let mut pages: Vec<u64> = rules.keys().copied().collect();
+        pages.sort();
+        assert!(ordering.check_consistency(&pages).is_err());
+    }
+
+    #[test]
+    fn test_rule_ordering_consistency() {
+        let (rules, _) = raw_input_to_rules_and_orders("1|2\n2|3\n4|5\n\n1\n");
+        let index = RuleIndex::new(&rules);
+        let ordering = RuleOrdering::new(&index);
+        assert_eq!(ordering.compare(&1, &2), Ordering::Less);
+        assert_eq!(ordering.compare(&3, &2), Ordering::Greater);
+        assert_eq!(ordering.compare(&1, &4), Ordering::Equal);
+        assert_eq!(ordering.check_consistency(&[4, 5]), Ok(()));
+
+        let err = ordering.check_consistency(&[1, 2, 3]).unwrap_err();
+        assert_eq!(
+            err,
+            Inconsistency::Intransitive(Intransitive {
+                first: 1,
+                second: 2,
+                third: 3,
+                relation: Ordering::Less
+            })
+        );
+        assert_eq!(err.to_string(), "1 < 2 and 2 < 3, but not 1 < 3");
+        // 1 and 4 have no rule, and neither do 4 and 2, but 1 comes before 2
+        assert!(matches!(
+            ordering.check_consistency(&[1, 4, 2]),
+            Err(Inconsistency::Intransitive(Intransitive {
+                relation: Ordering::Equal,
+                ..
+            }))
+        ));
+
+        // Rules going both ways make 1 < 2 and 2 < 1, which no triple check would notice
+        let (rules, _) = raw_input_to_rules_and_orders("1|2\n2|1\n1|3\n2|3\n\n1\n");
+        let index = RuleIndex::new(&rules);
+        let ordering = RuleOrdering::new(&index);
+        let err = ordering.sorted(&vec![2, 1]).unwrap_err();
+        assert_eq!(
+            err,
+            Inconsistency::Contradictory {
+                first: 2,
+                second: 1
+            }
+        );
+        assert_eq!(err.to_string(), "2 < 1 and 1 < 2");
+        assert!(ordering.sorted(&vec![3, 2, 1]).is_err());
+        assert!(ordering.sorted(&vec![1, 3]).is_ok());
+    }
+
+    #[test]
+    fn test_parse_input() {
+        let expected = raw_input_to_rules_and_orders("1|2\n2|3\n\n1,2,3\n3,1\n");
+        let messy = "\
+# Rules
+1|2\r
+ 2 | 3 \r
+\r
+\r
+# Updates\r
+1, 2,3\r
+3,1   \r
+\r
+";
+        assert_eq!(parse_input(messy).unwrap(), expected);
+
+        assert_eq!(
+            parse_input("1,2\n").unwrap(),
+            (HashMap::new(), vec![vec![1, 2]])
+        );
+        assert_eq!(parse_input("1|2\n").unwrap().1, Vec::<Order>::new());
+        assert_eq!(parse_input("").unwrap(), (HashMap::new(), vec![]));
+
+        let err = parse_input("1|2\n# comment\n1|x\n\n1,2\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 3: \"x\" isn't a page number");
+        assert_eq!(parse_input("1|2\n\n1,,2\n").unwrap_err().line, 3);
+        assert_eq!(parse_input("1,2\n1|2\n").unwrap_err().line, 2);
+    }
+
+    #[test]
+    fn test_export() {
+        let (rules, orders) = raw_input_to_rules_and_orders("1|2\n2|3\n1|3\n3|4\n\n2,1,3\n");
+        assert_eq!(
+            rules_to_json(&rules, None),
+            "{\"1\":[2,3],\"2\":[3],\"3\":[4],\"4\":[]}"
+        );
+        assert_eq!(
+            rules_to_json(&rules, Some(&orders[0])),
+            "{\"1\":[2,3],\"2\":[3],\"3\":[]}"
+        );
+
+        let dot = rules_to_dot(&rules, None);
+        assert!(dot.starts_with("digraph {"));
+        assert_eq!(dot.matches("->").count(), 4);
+        assert!(!dot.contains("red"));
+
+        // Only 1|2 is broken, by putting 2 first
+        let dot = rules_to_dot(&rules, Some(&orders[0]));
+        assert_eq!(dot.matches("->").count(), 3);
+        assert_eq!(dot.matches("color=red").count(), 1);
+        let red = dot.lines().find(|line| line.contains("color=red")).unwrap();
+        let labels: Vec<&str> = dot
+            .lines()
+            .filter(|line| line.contains("label = "))
+            .collect();
+        let index_of = |page: &str| {
+            labels
+                .iter()
+                .position(|line| line.contains(&format!("label = \"{}\"", page)))
+                .unwrap()
+        };
+        assert!(red.contains(&format!("{} -> {}", index_of("1"), index_of("2"))));
+    }
+
+    #[test]
+    fn test_count_orders() {
+        let (rules, orders) = raw_input_to_rules_and_orders("1|2\n1|3\n\n3,2,1\n1,2\n4,5,6,7\n\n");
+        assert_eq!(count_orders(&rules, &orders[0]), Some(2));
+        assert_eq!(
+            orderings(&rules, &orders[0]).collect::<Vec<_>>(),
+            vec![vec![1, 3, 2], vec![1, 2, 3]]
+        );
+        assert_eq!(possible_middle_pages(&rules, &orders[0]), vec![3, 2]);
+
+        assert_eq!(count_orders(&rules, &orders[1]), Some(1));
+        assert_eq!(possible_middle_pages(&rules, &orders[1]), vec![2]);
+        assert_eq!(count_orders(&rules, &orders[2]), Some(24));
+        assert_eq!(orderings(&rules, &orders[2]).count(), 24);
+        assert_eq!(orderings(&rules, &vec![]).collect::<Vec<_>>(), vec![vec![]]);
+        assert_eq!(count_orders(&rules, &(0..21).collect()), None);
+
+        let (rules, orders) = raw_input_to_rules_and_orders("1|2\n2|1\n\n1,2\n");
+        assert_eq!(count_orders(&rules, &orders[0]), Some(0));
+        assert_eq!(orderings(&rules, &orders[0]).next(), None);
+    }
+
+    #[test]
+    fn test_orderings_agree() {
+        let mut rng = XorShift(0x2024_0049);
+        for _ in 0..100 {
+            let pages = 1 + rng.next(7) as usize;
+            let (rules, orders) = generate_input(&mut rng, pages, pages, 3, pages);
+            for order in orders.iter() {
+                let all: Vec<Order> = orderings(&rules, order).collect();
+                assert_eq!(count_orders(&rules, order), Some(all.len() as u64));
+                assert!(all.iter().all(|o| is_order_valid(&rules, o)));
+                let distinct: HashSet<&Order> = all.iter().collect();
+                assert_eq!(distinct.len(), all.len());
+
+                let mut middles: Vec<u64> = all.iter().map(get_middle_page).collect();
+                middles.sort();
+                middles.dedup();
+                let mut possible = possible_middle_pages(&rules, order);
+                possible.sort();
+                assert_eq!(possible, middles);
+            }
+        }
+
+        // Every update in the real input has only the one order
+        let raw_input = read_to_string("./inputs/day5.txt").expect("Couldn't open day5.txt");
+        let (rules, orders) = raw_input_to_rules_and_orders(&raw_input);
+        for order in orders.iter().filter(|order| order.len() <= 13) {
+            assert_eq!(count_orders(&rules, order), Some(1));
+            assert_eq!(possible_middle_pages(&rules, order).len(), 1);
+        }
+    }
+
+    #[test]
+    fn test_rule_engine() {
+        let (rules, orders) = raw_input_to_rules_and_orders(EXAMPLE);
+        let mut engine = RuleEngine::new(&rules, orders);
+        assert_eq!(engine.valid_middle_total(), 143);
+
+        // Update 4 only breaks 97|75
+        assert_eq!(engine.remove_rule(97, 75), vec![3]);
+        assert_eq!(engine.remove_rule(97, 75), Vec::<usize>::new());
+        assert!(engine.is_valid(3));
+        assert_eq!(engine.valid_middle_total(), 143 + 47);
+
+        assert_eq!(engine.add_rule(29, 75), vec![0, 2]);
+        assert_eq!(engine.add_rule(29, 75), Vec::<usize>::new());
+        assert_eq!(engine.valid_middle_total(), 53 + 47);
+        assert_eq!(engine.remove_rule(29, 75), vec![0, 2]);
+        // A rule about a page nobody uses changes nothing
+        assert_eq!(engine.add_rule(1, 2), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_parse_rule_change() {
+        assert_eq!(parse_rule_change("+47|53"), Some(RuleChange::Add(47, 53)));
+        assert_eq!(
+            parse_rule_change("-47 | 53"),
+            Some(RuleChange::Remove(47, 53))
+        );
+        assert_eq!(parse_rule_change("47|53"), None);
+        assert_eq!(parse_rule_change("+47"), None);
+        // Multi-byte first characters mustn't be split in the middle
+        assert_eq!(parse_rule_change("\u{2212}47|53"), None);
+        assert_eq!(parse_rule_change("é"), None);
+    }
+
+    #[test]
+    fn test_rule_engine_matches_rebuilding() {
+        let mut rng = XorShift(0x2024_0050);
+        let (mut rules, orders) = generate_input(&mut rng, 40, 200, 50, 10);
+        let mut engine = RuleEngine::new(&rules, orders.clone());
+        for _ in 0..500 {
+            let before = 10 + rng.next(40) * 7;
+            let after = 10 + rng.next(40) * 7;
+            let afters = rules.entry(before).or_default();
+            if rng.next(2) == 0 {
+                afters.push(after);
+                engine.add_rule(before, after);
+            } else {
+                afters.retain(|page| *page != after);
+                engine.remove_rule(before, after);
+            }
+
+            let index = RuleIndex::new(&rules);
+            for (u, order) in orders.iter().enumerate() {
+                assert_eq!(engine.is_valid(u), index.is_valid(order));
+            }
+        }
+    }
 }
@@ -1,5 +1,7 @@
 use std::fs::read_to_string;
 
+use log::trace;
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 enum Heading {
     Up,    // ^
@@ -205,9 +207,11 @@ fn count_possible_obstruction_positions(
         };
 
         let next_spot = map.at(to_check.0, to_check.1);
-        println!(
+        trace!(
             "Checking ({}, {}) heading {:?}",
-            to_check.0, to_check.1, current_direction
+            to_check.0,
+            to_check.1,
+            current_direction
         );
         match next_spot {
             Some(spot) => {
@@ -1,10 +1,63 @@
+mod args;
 mod day1;
 mod day2;
 mod day3;
 mod day4;
 mod day5;
 mod day6;
+mod rng;
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// Writes log records to stderr, so stdout only has the answers
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!(
+                "[{}] {}: {}",
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
 
 fn main() {
-    day6::process_input("./inputs/day6.txt");
+    // Usage: aoc-2024 [-v|-vv] [dayN] [flags...]
+    let (verbosity, args): (Vec<String>, Vec<String>) = std::env::args().skip(1).partition(|arg| {
+        arg.len() > 1 && arg.starts_with('-') && arg[1..].chars().all(|c| c == 'v')
+    });
+    let level = match verbosity.iter().map(|arg| arg.len() - 1).sum::<usize>() {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    log::set_logger(&LOGGER).expect("Couldn't set up logging");
+    log::set_max_level(level);
+
+    let (day, flags) = match args.split_first() {
+        Some((day, flags)) => (day.as_str(), flags),
+        None => ("day6", &[][..]),
+    };
+
+    match day {
+        "day1" => day1::process_input("./inputs/day1.txt"),
+        "day2" => day2::process_input("./inputs/day2.txt", flags),
+        "day3" => day3::process_input("./inputs/day3.txt", flags),
+        "day4" => day4::process_input("./inputs/day4.txt", flags),
+        "day5" => day5::process_input("./inputs/day5.txt", flags),
+        "day6" => day6::process_input("./inputs/day6.txt"),
+        _ => eprintln!("Unknown day: {}", day),
+    }
 }
@@ -0,0 +1,13 @@
+/// Tiny xorshift generator, so random puzzles and test inputs are reproducible without pulling
+/// in a dependency. The seed mustn't be zero.
+pub struct XorShift(pub u64);
+
+impl XorShift {
+    /// The next number below `bound`
+    pub fn next(&mut self, bound: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % bound
+    }
+}